@@ -33,25 +33,25 @@ print(f"   ⚡ Speed up: {time1/time2:.1f}x faster")
 # Test 3: With debug=true
 print("\n3️⃣ With debug enabled:")
 print("   (Check stderr for debug output)")
-result3 = conn.execute("SELECT * FROM wizard('Seattle weather --debug')").fetchall()
+result3 = conn.execute("SELECT * FROM wizard('Seattle weather', debug := true)").fetchall()
 print(f"   Result rows: {len(result3)}")
 
 # Test 4: Bust cache
 print("\n4️⃣ Bust cache - force new API call:")
 start = time.time()
-result4 = conn.execute("SELECT * FROM wizard('Bitcoin price in USD --bust-cache')").fetchall()
+result4 = conn.execute("SELECT * FROM wizard('Bitcoin price in USD', refresh := true)").fetchall()
 time4 = time.time() - start
 print(f"   Result: {result4[0] if result4 else 'No data'}")
 print(f"   Time: {time4:.2f}s (should be similar to first call)")
 
 # Test 5: Relative time query
 print("\n5️⃣ Relative time query:")
-result5 = conn.execute("SELECT * FROM wizard('Weather for Seattle yesterday --debug')").fetchall()
+result5 = conn.execute("SELECT * FROM wizard('Weather for Seattle yesterday', debug := true)").fetchall()
 print(f"   Result rows: {len(result5)}")
 print("   (Check debug output to see if current date is used)")
 
 print("\n" + "=" * 70)
 print("✨ Test completed!")
-print("\nUsage: wizard('query [--debug] [--bust-cache]')")
-print("  - --debug: show debug output")
-print("  - --bust-cache: bypass cache and force new API call")
\ No newline at end of file
+print("\nUsage: wizard('query', debug := true, refresh := true)")
+print("  - debug: show debug output")
+print("  - refresh: bypass cache and force new API call")
\ No newline at end of file
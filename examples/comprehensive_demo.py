@@ -42,7 +42,8 @@ demos = [
     },
     {
         "title": "🔍 Debug Mode",
-        "query": "Bitcoin price --debug",
+        "query": "Bitcoin price",
+        "options": ", debug := true",
         "note": "Shows debug output (check stderr)"
     }
 ]
@@ -54,7 +55,7 @@ for demo in demos:
     print("-" * 60)
     
     try:
-        result = conn.execute(f"SELECT * FROM wizard('{demo['query']}')").fetchall()
+        result = conn.execute(f"SELECT * FROM wizard('{demo['query']}'{demo.get('options', '')})").fetchall()
         
         if result:
             # Check if it's an error
@@ -76,8 +77,8 @@ print("\n" + "=" * 80)
 print("\n✨ Features demonstrated:")
 print("  • Real API calls with http_get() from Rust")
 print("  • Response caching for performance")
-print("  • Debug mode with --debug flag")
-print("  • Cache busting with --bust-cache flag")
+print("  • Debug mode with debug := true")
+print("  • Cache busting with refresh := true")
 print("  • Error handling with descriptive messages")
 print("  • Comprehensive data columns")
 print("  • Support for relative time queries")
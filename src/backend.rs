@@ -0,0 +1,117 @@
+//! LLM backends that `wizard()` can generate code with, and the registry they are looked up in.
+//!
+//! The built-in `openai`, `anthropic`, `ollama` and `mock` backends are registered on first use. Crates
+//! embedding the extension can add their own with [`register_backend`]; they become available
+//! as `provider := '<name>'` and through the `wizard_provider` setting.
+
+use std::sync::{Arc, Mutex};
+
+pub use crate::llm::{parse_response, ColumnSchema, LLMResponse};
+use crate::mock::{self, MockFactory};
+use crate::providers::{AnthropicFactory, OllamaFactory, OpenAIFactory};
+
+/// One prompt to send to a backend.
+#[derive(Debug, Clone, Copy)]
+pub struct GenerateRequest<'a> {
+    pub prompt: &'a str,
+    /// The natural language query the prompt was built from.
+    pub query: &'a str,
+    /// Whether the prompt asks to fix a previous answer (code that threw, or rows that didn't
+    /// match the schema) rather than for new code.
+    pub revision: bool,
+    /// Sampling temperature; the backend's default if `None`.
+    pub temperature: Option<f32>,
+    /// Limit on the length of the answer; the backend's default if `None`.
+    pub max_tokens: Option<u32>,
+    pub debug: bool,
+}
+
+/// What a backend can do, so callers can adapt prompts to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Answers are bound to the `LLMResponse` shape (JSON schema, tool use), so the prompt
+    /// doesn't need to insist on bare JSON.
+    pub structured_output: bool,
+    /// Runs on this machine, so prompts never leave it.
+    pub local: bool,
+}
+
+/// A model that turns prompts into code and schemas.
+pub trait LlmBackend: Send + Sync {
+    /// The name the backend is registered under, e.g. `"openai"`.
+    fn name(&self) -> &str;
+
+    fn model(&self) -> &str;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Sends `request.prompt` and parses the answer. [`parse_response`] handles answers
+    /// given as JSON text.
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>>;
+}
+
+/// Creates backends for one registry name.
+pub trait BackendFactory: Send + Sync {
+    /// Model used when neither the query nor the settings name one.
+    fn default_model(&self) -> &str;
+
+    /// Whether the backend has what it needs (API keys, a server address) to be used. Only
+    /// configured backends are picked automatically and listed in errors.
+    fn is_configured(&self) -> bool {
+        true
+    }
+
+    fn create(&self, model: &str) -> Result<Box<dyn LlmBackend>, String>;
+}
+
+type Registry = Vec<(String, Arc<dyn BackendFactory>)>;
+
+lazy_static::lazy_static! {
+    static ref BACKENDS: Mutex<Registry> = {
+        let builtin: Registry = vec![
+            ("openai".to_string(), Arc::new(OpenAIFactory) as Arc<dyn BackendFactory>),
+            ("anthropic".to_string(), Arc::new(AnthropicFactory) as Arc<dyn BackendFactory>),
+            ("ollama".to_string(), Arc::new(OllamaFactory) as Arc<dyn BackendFactory>),
+            (mock::NAME.to_string(), Arc::new(MockFactory) as Arc<dyn BackendFactory>),
+        ];
+        Mutex::new(builtin)
+    };
+}
+
+/// Registers `factory` under `name` (lower-cased), replacing any backend of that name.
+pub fn register_backend(name: &str, factory: Arc<dyn BackendFactory>) {
+    let name = name.to_lowercase();
+    let mut backends = BACKENDS.lock().unwrap();
+    match backends.iter_mut().find(|(n, _)| *n == name) {
+        Some(entry) => entry.1 = factory,
+        None => backends.push((name, factory)),
+    }
+}
+
+pub fn factory(name: &str) -> Option<Arc<dyn BackendFactory>> {
+    BACKENDS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, factory)| factory.clone())
+}
+
+/// Registered backend names, built-in ones first.
+pub fn backend_names() -> Vec<String> {
+    BACKENDS.lock().unwrap().iter().map(|(name, _)| name.clone()).collect()
+}
+
+/// Names of the backends that are configured, in registration order.
+pub fn configured_backends() -> Vec<String> {
+    // Clone the factories first: `is_configured` may look at settings and secrets, which
+    // shouldn't happen while the registry is locked
+    let backends: Registry = BACKENDS.lock().unwrap().clone();
+    backends
+        .into_iter()
+        .filter(|(_, factory)| factory.is_configured())
+        .map(|(name, _)| name)
+        .collect()
+}
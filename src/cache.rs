@@ -0,0 +1,451 @@
+use duckdb::{
+    core::{DataChunkHandle, Inserter, LogicalTypeHandle, LogicalTypeId},
+    vtab::{BindInfo, InitInfo, TableFunctionInfo, VTab},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex},
+    time::Duration,
+};
+use chrono::Local;
+
+use crate::llm::ColumnSchema;
+use crate::options::{named_bool, parse_duration};
+use crate::result_cache;
+use crate::settings;
+
+const CACHE_FILE_NAME: &str = "responses.json";
+
+// Global cache for LLM responses, loaded from disk on first use
+lazy_static::lazy_static! {
+    static ref RESPONSE_CACHE: Mutex<ResponseCache> = Mutex::new(ResponseCache::load());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub query: String,
+    pub provider: String,
+    pub model: String,
+    pub prompt_version: u32,
+    /// The `columns` override the code was generated for, as `name type, ...`; empty if none.
+    #[serde(default)]
+    pub columns: String,
+    /// Fingerprint of a customised prompt template and rules; empty for the built-in prompt.
+    #[serde(default)]
+    pub prompt: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub key: CacheKey,
+    pub javascript_code: String,
+    pub schema: Vec<ColumnSchema>,
+    pub timestamp: chrono::DateTime<Local>,
+    /// Pinned entries never expire, whatever the TTL.
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub hits: u64,
+}
+
+impl CachedResponse {
+    pub fn is_expired(&self, ttl: CacheTtl) -> bool {
+        match ttl {
+            _ if self.pinned => false,
+            CacheTtl::Never => false,
+            CacheTtl::After(ttl) => (Local::now() - self.timestamp).to_std().unwrap_or_default() > ttl,
+        }
+    }
+}
+
+/// How long cached code stays valid before it is regenerated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheTtl {
+    Never,
+    After(Duration),
+}
+
+impl CacheTtl {
+    /// Parses TTLs such as `'12h'` or `'30m'`; `'never'` disables expiry.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "never" | "none" | "forever" | "infinite" => Ok(CacheTtl::Never),
+            other => parse_duration(other).map(CacheTtl::After),
+        }
+    }
+
+    /// The TTL from the `wizard_cache_ttl` setting, or `Never` when unset.
+    pub fn from_settings() -> Result<Self, String> {
+        match settings::get("wizard_cache_ttl") {
+            Some(value) => CacheTtl::parse(&value).map_err(|e| format!("wizard_cache_ttl: {}", e)),
+            None => Ok(CacheTtl::Never),
+        }
+    }
+}
+
+struct ResponseCache {
+    entries: HashMap<CacheKey, CachedResponse>,
+    path: Option<PathBuf>,
+}
+
+impl ResponseCache {
+    fn load() -> Self {
+        let mut cache = ResponseCache {
+            entries: HashMap::new(),
+            path: cache_path(),
+        };
+        cache.reload();
+        cache
+    }
+
+    /// Follows changes to `wizard_cache_dir`, dropping the entries read from the old file.
+    fn sync_path(&mut self) {
+        let path = cache_path();
+        if path != self.path {
+            self.path = path;
+            self.entries.clear();
+        }
+    }
+
+    /// Replaces the in-memory entries with those on disk, so entries written or evicted by
+    /// other processes are seen. The disk is authoritative; only hit counts not yet written
+    /// are carried over. Without persistence, or if the file can't be parsed, memory is kept.
+    fn reload(&mut self) {
+        self.sync_path();
+        let Some(disk) = self.read_disk() else {
+            return;
+        };
+        let mut entries = HashMap::with_capacity(disk.len());
+        for mut entry in disk {
+            if let Some(current) = self.entries.get(&entry.key).filter(|c| c.timestamp == entry.timestamp) {
+                entry.hits = entry.hits.max(current.hits);
+            }
+            entries.insert(entry.key.clone(), entry);
+        }
+        self.entries = entries;
+    }
+
+    /// The entries on disk: none if the file doesn't exist yet, `None` without persistence or
+    /// if the file can't be read.
+    fn read_disk(&self) -> Option<Vec<CachedResponse>> {
+        let path = self.path.as_ref()?;
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(Vec::new()),
+            Err(_) => None,
+        }
+    }
+
+    /// Writes the in-memory entries to disk. Callers `reload` right before mutating, so the
+    /// file ends up as the disk state plus that one change.
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let entries: Vec<&CachedResponse> = self.entries.values().collect();
+
+        // Write to a temporary file and rename so readers never see a partial file
+        let tmp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&tmp_path, serde_json::to_string_pretty(&entries)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Re-reads the disk state, applies `update` to it and saves. A cache that can't be written to disk still
+    /// works for this session.
+    fn update<T>(&mut self, debug: bool, update: impl FnOnce(&mut HashMap<CacheKey, CachedResponse>) -> T) -> T {
+        self.reload();
+        let result = update(&mut self.entries);
+        if let Err(e) = self.save() {
+            if debug {
+                eprintln!("Failed to persist wizard cache: {}", e);
+            }
+        }
+        result
+    }
+}
+
+fn cache_path() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join(CACHE_FILE_NAME))
+}
+
+/// Directory for the persistent cache: the `wizard_cache_dir` setting (or `WIZARD_CACHE_DIR`,
+/// where an empty value disables persistence), otherwise `$XDG_CACHE_HOME/duckdb_wizard` or
+/// `~/.cache/duckdb_wizard`.
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = settings::get("wizard_cache_dir") {
+        return Some(PathBuf::from(dir));
+    }
+    if env::var("WIZARD_CACHE_DIR").is_ok_and(|dir| dir.is_empty()) {
+        return None;
+    }
+    let base = env::var("XDG_CACHE_HOME")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var("HOME")
+                .or_else(|_| env::var("USERPROFILE"))
+                .ok()
+                .map(|home| PathBuf::from(home).join(".cache"))
+        })?;
+    Some(base.join("duckdb_wizard"))
+}
+
+/// Returns the cached entry for `key` unless it is older than `ttl`, counting the hit.
+pub fn get(key: &CacheKey, ttl: CacheTtl) -> Option<CachedResponse> {
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    cache.sync_path();
+    if !cache.entries.contains_key(key) {
+        cache.reload();
+    }
+    // Hit counts are only written to disk with the next change to the cache
+    let entry = cache.entries.get_mut(key).filter(|entry| !entry.is_expired(ttl))?;
+    entry.hits += 1;
+    Some(entry.clone())
+}
+
+pub fn insert(entry: CachedResponse, debug: bool) {
+    RESPONSE_CACHE.lock().unwrap().update(debug, |entries| {
+        entries.insert(entry.key.clone(), entry);
+    });
+}
+
+/// Marks the entry for `key` as pinned (or unpinned). Returns false if there is no such entry.
+pub fn set_pinned(key: &CacheKey, pinned: bool, debug: bool) -> bool {
+    RESPONSE_CACHE.lock().unwrap().update(debug, |entries| match entries.get_mut(key) {
+        Some(entry) => {
+            entry.pinned = pinned;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Pins (or unpins) every entry for `query`, whatever the provider or model. Returns the count.
+pub fn set_pinned_query(query: &str, pinned: bool) -> usize {
+    RESPONSE_CACHE.lock().unwrap().update(false, |entries| {
+        let mut count = 0;
+        for entry in entries.values_mut().filter(|entry| entry.key.query == query) {
+            entry.pinned = pinned;
+            count += 1;
+        }
+        count
+    })
+}
+
+/// Removes every entry for `query`, or every entry when `query` is `None`. Returns the count.
+pub fn evict(query: Option<&str>) -> usize {
+    RESPONSE_CACHE.lock().unwrap().update(false, |entries| {
+        let before = entries.len();
+        entries.retain(|key, _| query.is_some_and(|q| key.query != q));
+        before - entries.len()
+    })
+}
+
+/// A snapshot of all cached entries, oldest first.
+pub fn entries() -> Vec<CachedResponse> {
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    cache.reload();
+    let mut entries: Vec<CachedResponse> = cache.entries.values().cloned().collect();
+    entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.key.query.cmp(&b.key.query)));
+    entries
+}
+
+#[repr(C)]
+pub struct WizardCacheBindData {
+    entries: Vec<CachedResponse>,
+}
+
+#[repr(C)]
+pub struct WizardCacheInitData {
+    current_row: AtomicUsize,
+}
+
+/// `SELECT * FROM wizard_cache()` - lists the cached code and schemas.
+pub struct WizardCacheVTab;
+
+impl VTab for WizardCacheVTab {
+    type InitData = WizardCacheInitData;
+    type BindData = WizardCacheBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        bind.add_result_column("query", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("provider", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("model", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("schema", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("javascript_code", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("created_at", LogicalTypeHandle::from(LogicalTypeId::TimestampTZ));
+        bind.add_result_column("hits", LogicalTypeHandle::from(LogicalTypeId::Bigint));
+        bind.add_result_column("pinned", LogicalTypeHandle::from(LogicalTypeId::Boolean));
+        bind.add_result_column("prompt_version", LogicalTypeHandle::from(LogicalTypeId::Integer));
+
+        Ok(WizardCacheBindData { entries: entries() })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardCacheInitData {
+            current_row: AtomicUsize::new(0),
+        })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        let init_data = func.get_init_data();
+        let bind_data = func.get_bind_data();
+
+        let current_row = init_data.current_row.load(Ordering::Relaxed);
+        let chunk_size = std::cmp::min(2048, bind_data.entries.len().saturating_sub(current_row));
+        let end_row = current_row + chunk_size;
+
+        for (chunk_idx, entry) in bind_data.entries[current_row..end_row].iter().enumerate() {
+            output.flat_vector(0).insert(chunk_idx, entry.key.query.as_str());
+            output.flat_vector(1).insert(chunk_idx, entry.key.provider.as_str());
+            output.flat_vector(2).insert(chunk_idx, entry.key.model.as_str());
+            output.flat_vector(3).insert(chunk_idx, serde_json::to_string(&entry.schema)?.as_str());
+            output.flat_vector(4).insert(chunk_idx, entry.javascript_code.as_str());
+            output.flat_vector(5).as_mut_slice::<i64>()[chunk_idx] = entry.timestamp.timestamp_micros();
+            output.flat_vector(6).as_mut_slice::<i64>()[chunk_idx] = entry.hits as i64;
+            output.flat_vector(7).as_mut_slice::<bool>()[chunk_idx] = entry.pinned;
+            output.flat_vector(8).as_mut_slice::<i32>()[chunk_idx] = entry.key.prompt_version as i32;
+        }
+
+        output.set_len(chunk_size);
+        init_data.current_row.store(end_row, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![])
+    }
+}
+
+/// What a cache management function does. It runs on the first `func` call rather than in
+/// bind, so `EXPLAIN`, `DESCRIBE` and prepared statements leave the cache alone.
+enum CacheAction {
+    Evict(String),
+    Clear,
+    Pin(String, bool),
+}
+
+#[repr(C)]
+pub struct CacheCountBindData {
+    action: CacheAction,
+}
+
+#[repr(C)]
+pub struct CacheCountInitData {
+    done: AtomicBool,
+}
+
+/// Runs the action on the first call, returning how many entries it affected.
+fn run_action(init_data: &CacheCountInitData, bind_data: &CacheCountBindData, output: &mut DataChunkHandle) {
+    if init_data.done.swap(true, Ordering::Relaxed) {
+        output.set_len(0);
+        return;
+    }
+    let count = match &bind_data.action {
+        CacheAction::Evict(query) => evict(Some(query)),
+        CacheAction::Clear => {
+            result_cache::clear();
+            evict(None)
+        }
+        CacheAction::Pin(query, pinned) => set_pinned_query(query, *pinned),
+    };
+    output.flat_vector(0).as_mut_slice::<i64>()[0] = count as i64;
+    output.set_len(1);
+}
+
+/// `CALL wizard_cache_evict('bitcoin price')` - removes the cached code for a query.
+pub struct WizardCacheEvictVTab;
+
+impl VTab for WizardCacheEvictVTab {
+    type InitData = CacheCountInitData;
+    type BindData = CacheCountBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let query = bind.get_parameter(0).to_string();
+        bind.add_result_column("evicted", LogicalTypeHandle::from(LogicalTypeId::Bigint));
+        Ok(CacheCountBindData { action: CacheAction::Evict(query) })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(CacheCountInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        run_action(func.get_init_data(), func.get_bind_data(), output);
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
+    }
+}
+
+/// `CALL wizard_cache_clear()` - removes every cached entry, along with any cached rows.
+pub struct WizardCacheClearVTab;
+
+impl VTab for WizardCacheClearVTab {
+    type InitData = CacheCountInitData;
+    type BindData = CacheCountBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        bind.add_result_column("evicted", LogicalTypeHandle::from(LogicalTypeId::Bigint));
+        Ok(CacheCountBindData { action: CacheAction::Clear })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(CacheCountInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        run_action(func.get_init_data(), func.get_bind_data(), output);
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![])
+    }
+}
+
+/// `CALL wizard_cache_pin('bitcoin price')` - marks a query's cached code as vetted so it
+/// never expires. `pinned := false` unpins it again.
+pub struct WizardCachePinVTab;
+
+impl VTab for WizardCachePinVTab {
+    type InitData = CacheCountInitData;
+    type BindData = CacheCountBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let query = bind.get_parameter(0).to_string();
+        let pinned = named_bool(bind, "pinned").unwrap_or(true);
+        bind.add_result_column("updated", LogicalTypeHandle::from(LogicalTypeId::Bigint));
+        Ok(CacheCountBindData { action: CacheAction::Pin(query, pinned) })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(CacheCountInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        run_action(func.get_init_data(), func.get_bind_data(), output);
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
+    }
+
+    fn named_parameters() -> Option<Vec<(String, LogicalTypeHandle)>> {
+        Some(vec![("pinned".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean))])
+    }
+}
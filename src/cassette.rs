@@ -0,0 +1,311 @@
+//! Record/replay of LLM answers and of the `fetch()` calls made by generated code.
+//!
+//! With `wizard_cassette_mode` set to `record`, every LLM request and every `fetch()` is
+//! appended to the JSON file named by `wizard_cassette`. With `replay`, both are answered from
+//! that file instead, and anything it doesn't hold fails, so a recorded session runs again
+//! offline and gives the same rows.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::backend::{Capabilities, GenerateRequest, LlmBackend};
+use crate::llm::LLMResponse;
+use crate::settings;
+
+lazy_static::lazy_static! {
+    // Serializes read-modify-write cycles on cassette files
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+    // How many times each recorded LLM request has been replayed in this process
+    static ref REPLAYED: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Name of the function the record prelude exports to hand back the fetches it saw.
+pub const RECORDED_FETCHES_FN: &str = "wizard_recorded_fetches";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+/// The cassette configured by the `wizard_cassette` and `wizard_cassette_mode` settings.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    pub mode: Mode,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tape {
+    #[serde(default)]
+    llm: Vec<LlmInteraction>,
+    #[serde(default)]
+    http: Vec<HttpInteraction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LlmInteraction {
+    provider: String,
+    model: String,
+    query: String,
+    /// Whether this answered a request to fix previous code.
+    #[serde(default)]
+    revision: bool,
+    /// The prompt that was sent, for reference; replays don't match on it since it holds the
+    /// current date.
+    #[serde(default)]
+    prompt: String,
+    response: LLMResponse,
+}
+
+/// One `fetch()` made by generated code and the response it got.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpInteraction {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub request_body: Option<String>,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+/// Returns the configured cassette, if any.
+pub fn current() -> Result<Option<Cassette>, String> {
+    let Some(mode) = settings::get("wizard_cassette_mode") else {
+        return Ok(None);
+    };
+    let mode = match mode.trim().to_lowercase().as_str() {
+        "record" => Mode::Record,
+        "replay" => Mode::Replay,
+        _ => return Err(format!("wizard_cassette_mode must be 'record' or 'replay', got '{}'", mode)),
+    };
+    let path = settings::get("wizard_cassette")
+        .ok_or("wizard_cassette_mode is set, but wizard_cassette doesn't name a cassette file")?;
+    Ok(Some(Cassette {
+        mode,
+        path: PathBuf::from(path),
+    }))
+}
+
+fn load(path: &Path) -> Result<Tape, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read cassette '{}': {}", path.display(), e))?;
+    Ok(serde_json::from_str(&contents).map_err(|e| format!("Invalid cassette '{}': {}", path.display(), e))?)
+}
+
+/// Appends to the cassette file, creating it if needed.
+fn append(path: &Path, update: impl FnOnce(&mut Tape)) -> Result<(), Box<dyn std::error::Error>> {
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let mut tape = if path.exists() { load(path)? } else { Tape::default() };
+    update(&mut tape);
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::File::create(&tmp_path)?.write_all(serde_json::to_string_pretty(&tape)?.as_bytes())?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl Cassette {
+    /// A backend answering from the cassette as `provider` with `model` would have.
+    pub fn replay(&self, provider: &str, model: &str) -> Box<dyn LlmBackend> {
+        Box::new(ReplayBackend {
+            path: self.path.clone(),
+            provider: provider.to_string(),
+            model: model.to_string(),
+        })
+    }
+
+    /// Wraps `backend` so its answers are written to the cassette.
+    pub fn record(&self, provider: &str, backend: Box<dyn LlmBackend>) -> Box<dyn LlmBackend> {
+        Box::new(RecordingBackend {
+            path: self.path.clone(),
+            provider: provider.to_string(),
+            inner: backend,
+        })
+    }
+
+    /// JavaScript that replaces `fetch()` to record into, or replay from, the cassette. It runs
+    /// before the generated code.
+    pub fn fetch_prelude(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match self.mode {
+            Mode::Replay => {
+                let interactions = load(&self.path)?.http;
+                Ok(REPLAY_PRELUDE.replace("__INTERACTIONS__", &serde_json::to_string(&interactions)?))
+            }
+            Mode::Record => Ok(RECORD_PRELUDE.to_string()),
+        }
+    }
+
+    /// Appends the fetches made while recording.
+    pub fn record_fetches(&self, fetches: Vec<HttpInteraction>) -> Result<(), Box<dyn std::error::Error>> {
+        if fetches.is_empty() {
+            return Ok(());
+        }
+        append(&self.path, |tape| tape.http.extend(fetches))
+    }
+}
+
+/// Replayed requests are matched on method, URL and body. Repeats of the same request get the
+/// recorded responses in order, then the last one again.
+const REPLAY_PRELUDE: &str = r#"globalThis.fetch = (() => {
+    const interactions = __INTERACTIONS__;
+    const replayed = new Map();
+    return async (input, init = {}) => {
+        const request = new Request(input, init);
+        const body = init.body == null ? null : String(init.body);
+        const key = `${request.method} ${request.url}`;
+        const matching = interactions.filter(
+            (i) => i.method === request.method && i.url === request.url && (i.request_body ?? null) === body
+        );
+        if (matching.length === 0) {
+            throw new Error(`No recorded response for ${key} in the cassette`);
+        }
+        const count = replayed.get(key) ?? 0;
+        replayed.set(key, count + 1);
+        const i = matching[Math.min(count, matching.length - 1)];
+        const nullBody = [101, 204, 205, 304].includes(i.status);
+        return new Response(nullBody ? null : i.body, { status: i.status, headers: i.headers });
+    };
+})();
+"#;
+
+const RECORD_PRELUDE: &str = r#"globalThis.fetch = (() => {
+    const realFetch = globalThis.fetch;
+    globalThis.__WIZARD_RECORDED_FETCHES = [];
+    return async (input, init = {}) => {
+        const request = new Request(input, init);
+        const response = await realFetch(input, init);
+        globalThis.__WIZARD_RECORDED_FETCHES.push({
+            method: request.method,
+            url: request.url,
+            request_body: init.body == null ? null : String(init.body),
+            status: response.status,
+            headers: Object.fromEntries(response.headers),
+            body: await response.clone().text(),
+        });
+        return response;
+    };
+})();
+function wizard_recorded_fetches() {
+    return globalThis.__WIZARD_RECORDED_FETCHES;
+}
+"#;
+
+struct ReplayBackend {
+    path: PathBuf,
+    provider: String,
+    model: String,
+}
+
+impl LlmBackend for ReplayBackend {
+    fn name(&self) -> &str {
+        &self.provider
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            structured_output: true,
+            local: true,
+        }
+    }
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let matching: Vec<LlmInteraction> = load(&self.path)?
+            .llm
+            .into_iter()
+            .filter(|i| {
+                i.provider == self.provider
+                    && i.model == self.model
+                    && i.query == request.query
+                    && i.revision == request.revision
+            })
+            .collect();
+        if matching.is_empty() {
+            return Err(format!(
+                "No recorded LLM response for query '{}' (provider {}, model {}) in cassette '{}'",
+                request.query,
+                self.provider,
+                self.model,
+                self.path.display()
+            )
+            .into());
+        }
+
+        // Same order as recorded, so a repaired answer follows the one it repaired
+        let key = format!("{}\n{}\n{}\n{}\n{}", self.path.display(), self.provider, self.model, request.query, request.revision);
+        let count = {
+            let mut replayed = REPLAYED.lock().unwrap();
+            let count = replayed.entry(key).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        if request.debug {
+            eprintln!("Replaying LLM response from cassette '{}'", self.path.display());
+        }
+        Ok(matching[count.min(matching.len() - 1)].response.clone())
+    }
+}
+
+struct RecordingBackend {
+    path: PathBuf,
+    provider: String,
+    inner: Box<dyn LlmBackend>,
+}
+
+impl LlmBackend for RecordingBackend {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let response = self.inner.generate(request)?;
+        append(&self.path, |tape| {
+            tape.llm.push(LlmInteraction {
+                provider: self.provider.clone(),
+                model: self.inner.model().to_string(),
+                query: request.query.to_string(),
+                revision: request.revision,
+                prompt: request.prompt.to_string(),
+                response: response.clone(),
+            })
+        })?;
+        if request.debug {
+            eprintln!("Recorded LLM response to cassette '{}'", self.path.display());
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,497 @@
+//! The API catalog: APIs generated code should use, and how to call them.
+//!
+//! Entries come from `wizard_register_api`, from the JSON or YAML file named by the
+//! `wizard_api_catalog` setting, and from a few built-in free APIs. For each query the prompt
+//! lists only the entries that look relevant to it, so the LLM calls known endpoints instead of
+//! guessing.
+
+use duckdb::{
+    core::{DataChunkHandle, Inserter, LogicalTypeHandle, LogicalTypeId},
+    vtab::{BindInfo, InitInfo, TableFunctionInfo, VTab},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex},
+};
+
+use crate::options::{named_bool, named_varchar};
+use crate::settings;
+
+/// Most entries listed in one prompt.
+const MAX_PROMPT_ENTRIES: usize = 5;
+
+lazy_static::lazy_static! {
+    // APIs registered with wizard_register_api, for this process
+    static ref REGISTERED: Mutex<Vec<ApiEntry>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiEntry {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub base_url: String,
+    /// Name of the `http` secret holding the API's key.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// How the key is sent, e.g. `Authorization: Bearer <key>`.
+    #[serde(default)]
+    pub auth: Option<String>,
+    /// Example requests, e.g. `GET /repos/{owner}/{repo}/issues?state=open`.
+    #[serde(default)]
+    pub examples: Vec<String>,
+    /// What responses look like: a description or a sample JSON document.
+    #[serde(default)]
+    pub response: Option<serde_json::Value>,
+    /// Words that make the entry relevant to a query, in addition to its name and description.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Where the entry comes from: `builtin`, `sql` or the catalog file's path.
+    #[serde(skip)]
+    pub source: String,
+}
+
+/// A catalog file: a list of entries, or an object with an `apis` list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CatalogFile {
+    List(Vec<ApiEntry>),
+    Object { apis: Vec<ApiEntry> },
+}
+
+impl ApiEntry {
+    fn builtin(name: &str, description: &str, base_url: &str, examples: &[&str], keywords: &[&str]) -> Self {
+        ApiEntry {
+            name: name.to_string(),
+            description: description.to_string(),
+            base_url: base_url.to_string(),
+            secret: None,
+            auth: None,
+            examples: examples.iter().map(|e| e.to_string()).collect(),
+            response: None,
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            source: "builtin".to_string(),
+        }
+    }
+
+    /// How well the entry fits `words` from a query: the number of words it mentions.
+    fn score(&self, words: &[String]) -> usize {
+        let own = words_of(&format!("{} {} {}", self.name, self.description, self.keywords.join(" ")));
+        words
+            .iter()
+            .filter(|word| own.iter().any(|own| same_word(own, word)))
+            .count()
+    }
+
+    fn describe(&self) -> String {
+        let mut text = format!("- {}", self.name);
+        if !self.description.is_empty() {
+            text.push_str(&format!(": {}", self.description));
+        }
+        text.push_str(&format!("\n  Base URL: {}", self.base_url));
+        if let Some(secret) = &self.secret {
+            text.push_str(&format!("\n  Key: WIZARD_SECRETS[\"{}\"].key", secret));
+            if let Some(auth) = &self.auth {
+                text.push_str(&format!(", sent as {}", auth));
+            }
+        }
+        if !self.examples.is_empty() {
+            text.push_str("\n  Examples:");
+            for example in &self.examples {
+                text.push_str(&format!("\n    {}", example));
+            }
+        }
+        match &self.response {
+            Some(serde_json::Value::String(shape)) => text.push_str(&format!("\n  Response: {}", shape)),
+            Some(serde_json::Value::Null) | None => {}
+            Some(shape) => text.push_str(&format!("\n  Response: {}", shape)),
+        }
+        text
+    }
+}
+
+/// Words too common to say anything about which API fits.
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "with", "from", "that", "this", "these", "all", "any", "are", "per", "last", "next", "past",
+    "what", "which", "show", "list", "get", "current", "recent", "today", "each", "every",
+];
+
+/// Lower-cased words of 3 or more letters or digits, without stop words.
+fn words_of(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() >= 3)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// Words match if equal, or if one extends the other by a few letters ("quake", "quakes").
+fn same_word(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    a == b || (short.len() >= 4 && long.starts_with(short) && long.len() - short.len() <= 3)
+}
+
+fn builtin_entries() -> Vec<ApiEntry> {
+    vec![
+        ApiEntry::builtin(
+            "wttr.in",
+            "Weather forecasts and current conditions for any place",
+            "https://wttr.in",
+            &["GET https://wttr.in/Seattle?format=j1"],
+            &["weather", "forecast", "temperature", "rain", "wind", "humidity", "climate"],
+        ),
+        ApiEntry::builtin(
+            "USGS earthquakes",
+            "Earthquake feeds from the US Geological Survey",
+            "https://earthquake.usgs.gov/earthquakes/feed/v1.0",
+            &[
+                "GET https://earthquake.usgs.gov/earthquakes/feed/v1.0/summary/all_month.geojson",
+                "GET https://earthquake.usgs.gov/earthquakes/feed/v1.0/summary/significant_month.geojson",
+            ],
+            &["earthquake", "quake", "seismic", "magnitude", "tremor"],
+        ),
+        ApiEntry::builtin(
+            "CoinGecko",
+            "Cryptocurrency prices (free tier)",
+            "https://api.coingecko.com/api/v3",
+            &["GET https://api.coingecko.com/api/v3/simple/price?ids=bitcoin&vs_currencies=usd"],
+            &["crypto", "cryptocurrency", "bitcoin", "ethereum", "coin", "token", "btc", "eth"],
+        ),
+        ApiEntry {
+            response: Some(serde_json::Value::String(
+                "the current price is at result.chart.result[0].meta.regularMarketPrice and the symbol at \
+                 result.chart.result[0].meta.symbol"
+                    .to_string(),
+            )),
+            ..ApiEntry::builtin(
+                "Yahoo Finance",
+                "Stock quotes and price history",
+                "https://query1.finance.yahoo.com",
+                &["GET https://query1.finance.yahoo.com/v8/finance/chart/SYMBOL"],
+                &["stock", "share", "ticker", "quote", "nasdaq", "nyse", "market", "finance", "price"],
+            )
+        },
+        ApiEntry::builtin(
+            "ipapi",
+            "Location of the current IP address",
+            "https://ipapi.co",
+            &["GET https://ipapi.co/json/"],
+            &["address", "location", "geolocation", "country", "city"],
+        ),
+        ApiEntry::builtin(
+            "Official Joke API",
+            "Random jokes",
+            "https://official-joke-api.appspot.com",
+            &["GET https://official-joke-api.appspot.com/random_joke"],
+            &["joke", "funny", "humor"],
+        ),
+    ]
+}
+
+fn load_file(path: &str) -> Result<Vec<ApiEntry>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read API catalog '{}': {}", path, e))?;
+    let is_yaml = Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+    let file: CatalogFile = if is_yaml {
+        serde_yaml::from_str(&contents).map_err(|e| format!("Invalid API catalog '{}': {}", path, e))?
+    } else {
+        serde_json::from_str(&contents).map_err(|e| format!("Invalid API catalog '{}': {}", path, e))?
+    };
+    let mut entries = match file {
+        CatalogFile::List(entries) | CatalogFile::Object { apis: entries } => entries,
+    };
+    for entry in &mut entries {
+        entry.source = path.to_string();
+    }
+    Ok(entries)
+}
+
+/// Every entry: registered ones, then the catalog file's, then the built-in ones. A name used
+/// twice keeps its first entry, so registered and file entries override the built-in ones.
+pub fn entries() -> Result<Vec<ApiEntry>, String> {
+    let mut entries = REGISTERED.lock().unwrap().clone();
+    if let Some(path) = settings::get("wizard_api_catalog") {
+        entries.extend(load_file(&path)?);
+    }
+    entries.extend(builtin_entries());
+
+    let mut seen = std::collections::HashSet::new();
+    entries.retain(|entry| seen.insert(entry.name.to_lowercase()));
+    Ok(entries)
+}
+
+/// The entries relevant to `query`, best first. Catalog entries win ties over built-in ones.
+pub fn select(query: &str) -> Result<Vec<ApiEntry>, String> {
+    let words = words_of(query);
+    let mut scored: Vec<(usize, ApiEntry)> = entries()?
+        .into_iter()
+        .map(|entry| (entry.score(&words), entry))
+        .filter(|(score, _)| *score > 0)
+        .collect();
+    // Stable, so entries with the same score keep their catalog order
+    scored.sort_by(|a, b| b.0.cmp(&a.0));
+    Ok(scored.into_iter().take(MAX_PROMPT_ENTRIES).map(|(_, entry)| entry).collect())
+}
+
+/// The `{{catalog}}` section of the prompt, listing the entries `select` chose for the query.
+/// When none fits, the built-in free APIs are listed as examples of real endpoints.
+pub fn prompt_section(selected: &[ApiEntry]) -> String {
+    let (header, list): (&str, Vec<String>) = if selected.is_empty() {
+        ("Examples of real free APIs:", builtin_entries().iter().map(ApiEntry::describe).collect())
+    } else {
+        (
+            "APIs that fit this query. Prefer them over other APIs, and call only the endpoints and authentication \
+             described here:",
+            selected.iter().map(ApiEntry::describe).collect(),
+        )
+    };
+    format!("\n{}\n{}\n", header, list.join("\n"))
+}
+
+/// Whether the `selected` entries include some from the catalog rather than only built-in ones.
+pub fn customises_prompt(selected: &[ApiEntry]) -> bool {
+    selected.iter().any(|entry| entry.source != "builtin")
+}
+
+impl ApiEntry {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("API name must not be empty".to_string());
+        }
+        if !self.base_url.starts_with("http://") && !self.base_url.starts_with("https://") {
+            return Err(format!("base_url must be an http:// or https:// URL, got '{}'", self.base_url));
+        }
+        Ok(())
+    }
+}
+
+pub fn register(entry: ApiEntry, replace: bool) -> Result<(), String> {
+    entry.validate()?;
+    let mut registered = REGISTERED.lock().unwrap();
+    match registered.iter().position(|e| e.name.eq_ignore_ascii_case(&entry.name)) {
+        Some(_) if !replace => Err(format!(
+            "API '{}' is already registered. Pass replace := true to overwrite it",
+            entry.name
+        )),
+        Some(i) => {
+            registered[i] = entry;
+            Ok(())
+        }
+        None => {
+            registered.push(entry);
+            Ok(())
+        }
+    }
+}
+
+pub fn unregister(name: &str) -> bool {
+    let mut registered = REGISTERED.lock().unwrap();
+    let before = registered.len();
+    registered.retain(|e| !e.name.eq_ignore_ascii_case(name));
+    registered.len() != before
+}
+
+/// Splits a list parameter given as one line per item (or, for single-line values, comma
+/// separated items).
+fn split_list(value: &str, separator: char) -> Vec<String> {
+    value
+        .split(separator)
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[repr(C)]
+pub struct WizardRegisterApiBindData {
+    entry: ApiEntry,
+    replace: bool,
+}
+
+#[repr(C)]
+pub struct WizardApiInitData {
+    done: AtomicBool,
+}
+
+/// `CALL wizard_register_api('github', 'https://api.github.com', secret := 'github', ...)` - adds
+/// an API to the catalog for this session.
+pub struct WizardRegisterApiVTab;
+
+impl VTab for WizardRegisterApiVTab {
+    type InitData = WizardApiInitData;
+    type BindData = WizardRegisterApiBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let entry = ApiEntry {
+            name: bind.get_parameter(0).to_string(),
+            base_url: bind.get_parameter(1).to_string(),
+            description: named_varchar(bind, "description").unwrap_or_default(),
+            secret: named_varchar(bind, "secret"),
+            auth: named_varchar(bind, "auth"),
+            examples: named_varchar(bind, "examples").map(|e| split_list(&e, '\n')).unwrap_or_default(),
+            response: named_varchar(bind, "response").map(serde_json::Value::String),
+            keywords: named_varchar(bind, "keywords").map(|k| split_list(&k, ',')).unwrap_or_default(),
+            source: "sql".to_string(),
+        };
+        // Only checked here; the entry is registered when the statement runs.
+        entry.validate()?;
+
+        bind.add_result_column("name", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("base_url", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+
+        Ok(WizardRegisterApiBindData { entry, replace: named_bool(bind, "replace").unwrap_or(false) })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardApiInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        let bind_data = func.get_bind_data();
+        if func.get_init_data().done.swap(true, Ordering::Relaxed) {
+            output.set_len(0);
+            return Ok(());
+        }
+        register(bind_data.entry.clone(), bind_data.replace)?;
+        output.flat_vector(0).insert(0, bind_data.entry.name.as_str());
+        output.flat_vector(1).insert(0, bind_data.entry.base_url.as_str());
+        output.set_len(1);
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![
+            LogicalTypeHandle::from(LogicalTypeId::Varchar),
+            LogicalTypeHandle::from(LogicalTypeId::Varchar),
+        ])
+    }
+
+    fn named_parameters() -> Option<Vec<(String, LogicalTypeHandle)>> {
+        Some(vec![
+            ("description".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("secret".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("auth".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("examples".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("response".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("keywords".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("replace".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean)),
+        ])
+    }
+}
+
+#[repr(C)]
+pub struct WizardUnregisterApiBindData {
+    name: String,
+}
+
+/// `CALL wizard_unregister_api('github')` - removes an API registered with `wizard_register_api`.
+pub struct WizardUnregisterApiVTab;
+
+impl VTab for WizardUnregisterApiVTab {
+    type InitData = WizardApiInitData;
+    type BindData = WizardUnregisterApiBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let name = bind.get_parameter(0).to_string();
+        bind.add_result_column("removed", LogicalTypeHandle::from(LogicalTypeId::Boolean));
+        Ok(WizardUnregisterApiBindData { name })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardApiInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        if func.get_init_data().done.swap(true, Ordering::Relaxed) {
+            output.set_len(0);
+            return Ok(());
+        }
+        output.flat_vector(0).as_mut_slice::<bool>()[0] = unregister(&func.get_bind_data().name);
+        output.set_len(1);
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
+    }
+}
+
+#[repr(C)]
+pub struct WizardApisBindData {
+    entries: Vec<ApiEntry>,
+}
+
+#[repr(C)]
+pub struct WizardApisInitData {
+    current_row: AtomicUsize,
+}
+
+/// `SELECT * FROM wizard_apis()` - lists the catalog. `query := '...'` lists only the entries
+/// the prompt for that query would mention, best first.
+pub struct WizardApisVTab;
+
+impl VTab for WizardApisVTab {
+    type InitData = WizardApisInitData;
+    type BindData = WizardApisBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let entries = match named_varchar(bind, "query") {
+            Some(query) => select(&query)?,
+            None => entries()?,
+        };
+
+        bind.add_result_column("name", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("base_url", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("description", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("secret", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("keywords", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("source", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+
+        Ok(WizardApisBindData { entries })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardApisInitData {
+            current_row: AtomicUsize::new(0),
+        })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        let init_data = func.get_init_data();
+        let bind_data = func.get_bind_data();
+
+        let current_row = init_data.current_row.load(Ordering::Relaxed);
+        let chunk_size = std::cmp::min(2048, bind_data.entries.len().saturating_sub(current_row));
+        let end_row = current_row + chunk_size;
+
+        for (chunk_idx, entry) in bind_data.entries[current_row..end_row].iter().enumerate() {
+            output.flat_vector(0).insert(chunk_idx, entry.name.as_str());
+            output.flat_vector(1).insert(chunk_idx, entry.base_url.as_str());
+            output.flat_vector(2).insert(chunk_idx, entry.description.as_str());
+            let mut secret = output.flat_vector(3);
+            match &entry.secret {
+                Some(name) => secret.insert(chunk_idx, name.as_str()),
+                None => secret.set_null(chunk_idx),
+            }
+            output.flat_vector(4).insert(chunk_idx, entry.keywords.join(", ").as_str());
+            output.flat_vector(5).insert(chunk_idx, entry.source.as_str());
+        }
+
+        output.set_len(chunk_size);
+        init_data.current_row.store(end_row, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![])
+    }
+
+    fn named_parameters() -> Option<Vec<(String, LogicalTypeHandle)>> {
+        Some(vec![("query".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar))])
+    }
+}
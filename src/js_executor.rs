@@ -1,46 +1,124 @@
 use rustyscript::{Runtime, Module, RuntimeOptions};
-use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
 
-pub struct JsExecutor;
+use crate::cassette::{Cassette, HttpInteraction, Mode, RECORDED_FETCHES_FN};
+use crate::secrets::{self, Secret};
+
+pub struct JsExecutor {
+    timeout: Option<Duration>,
+    secrets: Vec<Secret>,
+    cassette: Option<Cassette>,
+}
+
+/// Hashes everything besides the code that can change what running it returns.
+impl Hash for JsExecutor {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.timeout.hash(state);
+        for secret in &self.secrets {
+            (&secret.name, &secret.key, &secret.scope).hash(state);
+        }
+        self.cassette.as_ref().map(|c| (c.mode, &c.path)).hash(state);
+    }
+}
 
 impl JsExecutor {
     pub fn new() -> Self {
-        JsExecutor
+        JsExecutor {
+            timeout: None,
+            secrets: Vec::new(),
+            cassette: None,
+        }
     }
 
-    pub fn execute_code(&self, code: &str, debug: bool) -> Result<Vec<HashMap<String, JsValue>>, Box<dyn std::error::Error>> {
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// `http` secrets the code can use through the global `WIZARD_SECRETS` object.
+    pub fn with_secrets(mut self, secrets: Vec<Secret>) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    /// Records `fetch()` calls to, or replays them from, `cassette`.
+    pub fn with_cassette(mut self, cassette: Option<Cassette>) -> Self {
+        self.cassette = cassette;
+        self
+    }
+
+    pub fn execute_code(&self, code: &str, debug: bool) -> Result<Vec<JsRow>, Box<dyn std::error::Error>> {
         if debug {
             eprintln!("Executing JavaScript code:\n{}", code);
         }
 
-        // Create runtime with default options
+        // Create runtime with default options, bounded by the timeout if one was given
         // The url_import feature enables https:// imports automatically
-        let mut runtime = Runtime::new(RuntimeOptions::default())?;
+        let mut runtime_options = RuntimeOptions::default();
+        if let Some(timeout) = self.timeout {
+            runtime_options.timeout = timeout;
+        }
+        let mut runtime = Runtime::new(runtime_options)?;
 
-        // Wrap the code in a module with an exported function
-        let module_code = format!(r#"
+        // Wrap the code in a module with an exported function. Imports are hoisted, so the
+        // secrets and the cassette's fetch() can come first.
+        let (prelude, exports) = match &self.cassette {
+            Some(cassette) if cassette.mode == Mode::Record => {
+                (cassette.fetch_prelude()?, format!("fetch_data, {}", RECORDED_FETCHES_FN))
+            }
+            Some(cassette) => (cassette.fetch_prelude()?, "fetch_data".to_string()),
+            None => (String::new(), "fetch_data".to_string()),
+        };
+        let module_code = format!(r#"{}
+{}
 {}
 
-export {{ fetch_data }};
-"#, code);
+export {{ {} }};
+"#, secrets::fetch_prelude(&self.secrets), prelude, code, exports);
 
         // Create and load the module
         let module = Module::new("wizard.js", &module_code);
         let module_handle = runtime.load_module(&module)?;
 
-        // Call the async fetch_data function
+        // Call the async fetch_data function. Async calls bypass the runtime's own timeout, so
+        // it is applied here.
+        let timeout = self.timeout;
         let result: serde_json::Value = runtime.tokio_runtime().block_on(async {
-            runtime.call_function_async(
+            let call = runtime.call_function_async(
                 Some(&module_handle),
                 "fetch_data",
                 rustyscript::json_args!()
-            ).await
+            );
+            let result: Result<serde_json::Value, Box<dyn std::error::Error>> = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, call).await {
+                    Ok(result) => Ok(result?),
+                    Err(_) => Err(format!("JavaScript execution timed out after {:?}", timeout).into()),
+                },
+                None => Ok(call.await?),
+            };
+            result
         })?;
 
         if debug {
             eprintln!("Result: {}", serde_json::to_string_pretty(&result)?);
         }
 
+        if let Some(cassette) = self.cassette.as_ref().filter(|c| c.mode == Mode::Record) {
+            let fetches: serde_json::Value = runtime.tokio_runtime().block_on(async {
+                runtime.call_function_async(
+                    Some(&module_handle),
+                    RECORDED_FETCHES_FN,
+                    rustyscript::json_args!()
+                ).await
+            })?;
+            let fetches: Vec<HttpInteraction> = serde_json::from_value(fetches)?;
+            if debug {
+                eprintln!("Recorded {} fetch() calls to cassette '{}'", fetches.len(), cassette.path.display());
+            }
+            cassette.record_fetches(fetches)?;
+        }
+
         // Convert result to expected format
         let mut rows = Vec::new();
         
@@ -48,20 +126,12 @@ export {{ fetch_data }};
             serde_json::Value::Array(array) => {
                 for item in array {
                     if let serde_json::Value::Object(obj) = item {
-                        let mut row = HashMap::new();
-                        for (key, value) in obj {
-                            row.insert(key, json_to_value(value));
-                        }
-                        rows.push(row);
+                        rows.push(json_to_row(obj));
                     }
                 }
             },
             serde_json::Value::Object(obj) => {
-                let mut row = HashMap::new();
-                for (key, value) in obj {
-                    row.insert(key, json_to_value(value));
-                }
-                rows.push(row);
+                rows.push(json_to_row(obj));
             },
             _ => return Err("Unexpected result type from JavaScript execution".into()),
         }
@@ -70,15 +140,66 @@ export {{ fetch_data }};
     }
 }
 
+/// One object returned by `fetch_data()`, with its keys in JavaScript order.
+#[derive(Debug, Clone, Default)]
+pub struct JsRow(pub Vec<(String, JsValue)>);
+
+impl JsRow {
+    pub fn get(&self, key: &str) -> Option<&JsValue> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, JsValue)> {
+        self.0.iter()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(self.0.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum JsValue {
     String(String),
     Float(f64),
     Integer(i64),
     Boolean(bool),
+    List(Vec<JsValue>),
+    Object(Vec<(String, JsValue)>),
     Null,
 }
 
+impl JsValue {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            JsValue::String(s) => serde_json::Value::String(s.clone()),
+            JsValue::Float(f) => serde_json::Number::from_f64(*f)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            JsValue::Integer(i) => serde_json::Value::from(*i),
+            JsValue::Boolean(b) => serde_json::Value::Bool(*b),
+            JsValue::List(items) => serde_json::Value::Array(items.iter().map(JsValue::to_json).collect()),
+            JsValue::Object(fields) => serde_json::Value::Object(
+                fields.iter().map(|(k, v)| (k.clone(), v.to_json())).collect(),
+            ),
+            JsValue::Null => serde_json::Value::Null,
+        }
+    }
+
+    /// Looks up a field of an object value.
+    pub fn get(&self, key: &str) -> Option<&JsValue> {
+        match self {
+            JsValue::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+// serde_json is built with `preserve_order`, so object keys come out in JavaScript order
+fn json_to_row(obj: serde_json::Map<String, serde_json::Value>) -> JsRow {
+    JsRow(obj.into_iter().map(|(k, v)| (k, json_to_value(v))).collect())
+}
+
 fn json_to_value(value: serde_json::Value) -> JsValue {
     match value {
         serde_json::Value::String(s) => JsValue::String(s),
@@ -92,7 +213,10 @@ fn json_to_value(value: serde_json::Value) -> JsValue {
             }
         },
         serde_json::Value::Bool(b) => JsValue::Boolean(b),
+        serde_json::Value::Array(items) => JsValue::List(items.into_iter().map(json_to_value).collect()),
+        serde_json::Value::Object(obj) => JsValue::Object(
+            obj.into_iter().map(|(k, v)| (k, json_to_value(v))).collect(),
+        ),
         serde_json::Value::Null => JsValue::Null,
-        _ => JsValue::String(value.to_string()),
     }
 }
\ No newline at end of file
@@ -2,11 +2,23 @@ extern crate duckdb;
 extern crate duckdb_loadable_macros;
 extern crate libduckdb_sys;
 
+pub mod backend;
+mod cache;
+mod cassette;
+mod catalog;
 mod llm;
 mod js_executor;
+mod mock;
+mod options;
+mod prompt;
+mod providers;
+mod result_cache;
+mod secrets;
+mod settings;
+mod types;
 
 use duckdb::{
-    core::{DataChunkHandle, Inserter, LogicalTypeHandle, LogicalTypeId},
+    core::{DataChunkHandle, LogicalTypeHandle, LogicalTypeId},
     vtab::{BindInfo, InitInfo, TableFunctionInfo, VTab},
     Connection, Result,
 };
@@ -14,33 +26,28 @@ use duckdb_loadable_macros::duckdb_entrypoint_c_api;
 use libduckdb_sys as ffi;
 use std::{
     error::Error,
-    ffi::CString,
     sync::atomic::Ordering,
-    collections::HashMap,
-    sync::Mutex,
 };
 use chrono::Local;
 
-use crate::llm::{LLMClient, ColumnSchema};
-use crate::js_executor::{JsExecutor, JsValue};
-
-// Global cache for LLM responses
-lazy_static::lazy_static! {
-    static ref RESPONSE_CACHE: Mutex<HashMap<String, CachedResponse>> = Mutex::new(HashMap::new());
-}
-
-#[derive(Clone)]
-struct CachedResponse {
-    javascript_code: String,
-    schema: Vec<ColumnSchema>,
-    timestamp: chrono::DateTime<Local>,
-}
+use crate::catalog::{ApiEntry, WizardApisVTab, WizardRegisterApiVTab, WizardUnregisterApiVTab};
+use crate::cache::{
+    CacheKey, CacheTtl, CachedResponse, WizardCacheClearVTab, WizardCacheEvictVTab, WizardCachePinVTab,
+    WizardCacheVTab,
+};
+use crate::llm::{LLMClient, LLMResponse, ColumnSchema};
+use crate::js_executor::{JsExecutor, JsRow, JsValue};
+use crate::options::{named_columns, named_int64, named_result_ttl, OnError, WizardOptions};
+use crate::secrets::{WizardCreateSecretVTab, WizardDropSecretVTab, WizardSecretsVTab};
+use crate::settings::WizardSetVTab;
+use crate::types::{any_logical_type, check_rows, conformance_problems, infer_columns, to_varchar, write_column, ColumnType};
 
 #[repr(C)]
 struct WizardBindData {
     query: String,
     schema: Vec<ColumnSchema>,
-    data: Vec<HashMap<String, JsValue>>,
+    column_types: Vec<ColumnType>,
+    data: Vec<JsRow>,
 }
 
 #[repr(C)]
@@ -55,79 +62,162 @@ impl VTab for WizardVTab {
     type BindData = WizardBindData;
 
     fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
-        let full_query = bind.get_parameter(0).to_string();
+        let query = bind.get_parameter(0).to_string();
+        let options = WizardOptions::from_bind(bind)?;
+        let debug = options.debug;
         
-        // Check if query ends with debug/cache flags
-        let mut query = full_query.clone();
-        let mut debug = false;
-        let mut bust_cache = false;
+        // Selected once, for both the cache key and the prompt
+        let apis = catalog::select(&query)?;
+        let provider = llm::resolve_provider(options.provider.as_deref());
+        let cache_key = CacheKey {
+            query: query.clone(),
+            model: llm::resolve_model(&provider, options.model.as_deref()),
+            provider,
+            prompt_version: llm::PROMPT_VERSION,
+            columns: options.columns.as_deref().map(describe_columns).unwrap_or_default(),
+            prompt: prompt::fingerprint(&apis)?,
+        };
         
-        // Simple flag parsing from query string
-        if query.ends_with(" --debug") {
-            debug = true;
-            query = query.trim_end_matches(" --debug").to_string();
-        }
-        if query.ends_with(" --bust-cache") {
-            bust_cache = true;
-            query = query.trim_end_matches(" --bust-cache").to_string();
-        }
-        if query.ends_with(" --debug --bust-cache") {
-            debug = true;
-            bust_cache = true;
-            query = query.trim_end_matches(" --debug --bust-cache").to_string();
-        }
+        let ttl = match options.ttl {
+            Some(ttl) => ttl,
+            None => CacheTtl::from_settings()?,
+        };
         
-        // Check cache first (unless bust_cache is true)
-        let cached_response = if !bust_cache {
-            RESPONSE_CACHE.lock().unwrap().get(&query).cloned()
+        // Check cache first (unless refresh := true), regenerating entries older than the TTL
+        let cached_response = if !options.refresh {
+            cache::get(&cache_key, ttl)
         } else {
             None
         };
         
-        let (javascript_code, schema) = if let Some(cached) = cached_response {
+        let requested: Option<Vec<ColumnSchema>> = options.columns.as_ref().map(|columns| {
+            columns
+                .iter()
+                .map(|(name, col_type)| ColumnSchema::new(name, &col_type.to_string()))
+                .collect()
+        });
+        
+        // The LLM client is only needed on a cache miss or to repair code
+        let mut llm_client: Option<LLMClient> = None;
+        
+        let (mut response, mut changed) = if let Some(cached) = cached_response {
             if debug {
                 eprintln!("Using cached response for query: {}", query);
             }
-            (cached.javascript_code, cached.schema)
+            (LLMResponse { javascript_code: cached.javascript_code, schema: cached.schema }, false)
         } else {
-            // Initialize LLM client and get code + schema
-            let llm_client = LLMClient::new()
-                .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::Other, e)) as Box<dyn std::error::Error>)?;
-            
-            let llm_response = llm_client.generate_data_fetch_code(&query, debug)?;
-            
-            // Cache the response
-            RESPONSE_CACHE.lock().unwrap().insert(
-                query.clone(),
+            (client(&mut llm_client, &options, &apis)?.generate_data_fetch_code(&query, requested.as_deref(), debug)?, true)
+        };
+        
+        // Execute the JavaScript code to get the data, asking the LLM to repair it while the
+        // rows don't match the schema
+        let executor = JsExecutor::new()
+            .with_timeout(options.timeout)
+            .with_secrets(secrets::http_secrets())
+            .with_cassette(cassette::current()?);
+        let (mut data, healed) = execute_healing(&executor, &mut response, &mut llm_client, &query, requested.as_deref(), &apis, &options)?;
+        changed |= healed;
+        let mut attempt = 0;
+        let (problems, reported_error) = loop {
+            let expected = match &options.columns {
+                Some(columns) => columns.clone(),
+                None => response
+                    .schema
+                    .iter()
+                    .map(|col| (col.name.clone(), ColumnType::from_schema(col)))
+                    .collect(),
+            };
+            // An error row means the API failed, not that the code disagrees with its schema.
+            // Only a requested column can make `error` part of the data: generated code that
+            // reports errors often declares those keys in its own schema too.
+            if let Some(message) = error_row(&data, options.columns.as_deref().unwrap_or_default()) {
+                break (Vec::new(), Some(message));
+            }
+            let problems = conformance_problems(&data, &expected);
+            if problems.is_empty() || attempt == options.repair_attempts {
+                break (problems, None);
+            }
+            attempt += 1;
+            if debug {
+                eprintln!(
+                    "Rows don't match the schema, repair attempt {}/{}:\n  {}",
+                    attempt,
+                    options.repair_attempts,
+                    problems.join("\n  ")
+                );
+            }
+            let sample = &data[..data.len().min(REPAIR_SAMPLE_ROWS)];
+            response = client(&mut llm_client, &options, &apis)?.repair_code(&query, &response, requested.as_deref(), &problems, sample, debug)?;
+            (data, _) = execute_healing(&executor, &mut response, &mut llm_client, &query, requested.as_deref(), &apis, &options)?;
+            changed = true;
+        };
+        
+        if !problems.is_empty() {
+            // Declared columns are a contract; an LLM schema is best effort and misfits become NULLs
+            if options.columns.is_some() {
+                return Err(problems.join("; ").into());
+            }
+            if debug {
+                eprintln!("Rows still don't match the schema:\n  {}", problems.join("\n  "));
+            }
+        }
+        
+        if let Some(message) = &reported_error {
+            if debug {
+                eprintln!("Generated code returned an error row: {}", message);
+            }
+            match options.on_error {
+                OnError::Raise => return Err(format!("wizard: {}", message).into()),
+                OnError::Row => {}
+                OnError::Empty => data.clear(),
+            }
+        }
+        
+        // Cache the code once it has run, so a repaired version replaces the original. Code
+        // that reported an error is never cached.
+        if changed && reported_error.is_none() {
+            cache::insert(
                 CachedResponse {
-                    javascript_code: llm_response.javascript_code.clone(),
-                    schema: llm_response.schema.clone(),
+                    key: cache_key.clone(),
+                    javascript_code: response.javascript_code.clone(),
+                    schema: response.schema.clone(),
                     timestamp: Local::now(),
-                }
+                    pinned: false,
+                    hits: 0,
+                },
+                debug,
             );
-            
-            (llm_response.javascript_code, llm_response.schema)
-        };
+        }
         
-        // Add columns based on the schema
-        for col in &schema {
-            let logical_type = match col.data_type.as_str() {
-                "varchar" => LogicalTypeId::Varchar,
-                "double" => LogicalTypeId::Double,
-                "bigint" => LogicalTypeId::Bigint,
-                "date" => LogicalTypeId::Varchar, // We'll use varchar for dates for simplicity
-                _ => LogicalTypeId::Varchar,
-            };
-            bind.add_result_column(&col.name, LogicalTypeHandle::from(logical_type));
+        if options.pin {
+            cache::set_pinned(&cache_key, true, debug);
         }
         
-        // Execute the JavaScript code to get the data
-        let executor = JsExecutor::new();
-        let data = executor.execute_code(&javascript_code, debug)?;
+        // Add columns based on the schema, or exactly the requested columns if given. An error row
+        // passed through with on_error := 'row' keeps its own columns.
+        let (schema, column_types): (Vec<ColumnSchema>, Vec<ColumnType>) = match (requested, &options.columns) {
+            _ if reported_error.is_some() && options.on_error == OnError::Row => infer_columns(&data)
+                .into_iter()
+                .map(|(name, col_type)| (ColumnSchema::new(&name, &col_type.to_string()), col_type))
+                .unzip(),
+            (Some(requested), Some(columns)) => (requested, columns.iter().map(|(_, t)| t.clone()).collect()),
+            _ => {
+                let column_types = response.schema.iter().map(ColumnType::from_schema).collect();
+                (response.schema, column_types)
+            }
+        };
+        for (col, col_type) in schema.iter().zip(&column_types) {
+            bind.add_result_column(&col.name, col_type.logical_type());
+        }
+        
+        if let Some(max_rows) = options.max_rows {
+            data.truncate(max_rows);
+        }
         
         Ok(WizardBindData { 
             query,
             schema,
+            column_types,
             data,
         })
     }
@@ -154,69 +244,10 @@ impl VTab for WizardVTab {
         let end_row = current_row + chunk_size;
         
         // Fill columns based on schema
-        for (col_idx, col_schema) in bind_data.schema.iter().enumerate() {
-            match col_schema.data_type.as_str() {
-                "varchar" => {
-                    for (chunk_idx, row_idx) in (current_row..end_row).enumerate() {
-                        let row = &bind_data.data[row_idx];
-                        let value = row.get(&col_schema.name)
-                            .and_then(|v| match v {
-                                JsValue::String(s) => Some(s.as_str()),
-                                _ => None,
-                            })
-                            .unwrap_or("");
-                        let c_str = CString::new(value)?;
-                        output.flat_vector(col_idx).insert(chunk_idx, c_str);
-                    }
-                },
-                "double" => {
-                    let mut vec = output.flat_vector(col_idx);
-                    let slice = vec.as_mut_slice::<f64>();
-                    for (chunk_idx, row_idx) in (current_row..end_row).enumerate() {
-                        let row = &bind_data.data[row_idx];
-                        let value = row.get(&col_schema.name)
-                            .and_then(|v| match v {
-                                JsValue::Float(f) => Some(*f),
-                                JsValue::Integer(i) => Some(*i as f64),
-                                _ => None,
-                            })
-                            .unwrap_or(0.0);
-                        slice[chunk_idx] = value;
-                    }
-                },
-                "bigint" => {
-                    let mut vec = output.flat_vector(col_idx);
-                    let slice = vec.as_mut_slice::<i64>();
-                    for (chunk_idx, row_idx) in (current_row..end_row).enumerate() {
-                        let row = &bind_data.data[row_idx];
-                        let value = row.get(&col_schema.name)
-                            .and_then(|v| match v {
-                                JsValue::Integer(i) => Some(*i),
-                                JsValue::Float(f) => Some(*f as i64),
-                                _ => None,
-                            })
-                            .unwrap_or(0);
-                        slice[chunk_idx] = value;
-                    }
-                },
-                _ => {
-                    // Default to varchar
-                    for (chunk_idx, row_idx) in (current_row..end_row).enumerate() {
-                        let row = &bind_data.data[row_idx];
-                        let value = row.get(&col_schema.name)
-                            .map(|v| match v {
-                                JsValue::String(s) => s.clone(),
-                                JsValue::Float(f) => f.to_string(),
-                                JsValue::Integer(i) => i.to_string(),
-                                JsValue::Boolean(b) => b.to_string(),
-                                JsValue::Null => "".to_string(),
-                            })
-                            .unwrap_or_else(|| "".to_string());
-                        let c_str = CString::new(value)?;
-                        output.flat_vector(col_idx).insert(chunk_idx, c_str);
-                    }
-                }
-            }
+        let rows = &bind_data.data[current_row..end_row];
+        for (col_idx, (col_schema, col_type)) in bind_data.schema.iter().zip(&bind_data.column_types).enumerate() {
+            let values: Vec<Option<&JsValue>> = rows.iter().map(|row| row.get(&col_schema.name)).collect();
+            write_column(output, col_idx, col_type, &values)?;
         }
         
         output.set_len(chunk_size);
@@ -228,14 +259,96 @@ impl VTab for WizardVTab {
     fn parameters() -> Option<Vec<LogicalTypeHandle>> {
         Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
     }
+
+    fn named_parameters() -> Option<Vec<(String, LogicalTypeHandle)>> {
+        Some(WizardOptions::named_parameters())
+    }
 }
 
 const EXTENSION_NAME: &str = env!("CARGO_PKG_NAME");
 
+/// Creates the LLM client for `options` the first time it's needed.
+fn client<'a>(
+    client: &'a mut Option<LLMClient>,
+    options: &WizardOptions,
+    apis: &[ApiEntry],
+) -> Result<&'a LLMClient, Box<dyn std::error::Error>> {
+    if client.is_none() {
+        let created = LLMClient::new(options.provider.as_deref(), options.model.as_deref())?
+            .with_temperature(options.temperature)
+            .with_max_tokens(options.max_tokens)
+            .with_apis(apis.to_vec());
+        *client = Some(created);
+    }
+    Ok(client.as_ref().unwrap())
+}
+
+/// Runs the code in `response`. If it throws, the LLM is asked for a fixed version up to
+/// `heal_attempts` times; returns the rows and whether `response` was replaced.
+fn execute_healing(
+    executor: &JsExecutor,
+    response: &mut LLMResponse,
+    llm_client: &mut Option<LLMClient>,
+    query: &str,
+    requested: Option<&[ColumnSchema]>,
+    apis: &[ApiEntry],
+    options: &WizardOptions,
+) -> Result<(Vec<JsRow>, bool), Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    loop {
+        let error = match result_cache::execute_cached(
+            executor,
+            &response.javascript_code,
+            options.result_ttl,
+            options.refresh,
+            |rows| error_row(rows, options.columns.as_deref().unwrap_or_default()).is_none(),
+            options.debug,
+        ) {
+            Ok(rows) => return Ok((rows, attempt > 0)),
+            Err(e) if attempt < options.heal_attempts => e.to_string(),
+            Err(e) => return Err(e),
+        };
+        attempt += 1;
+        if options.debug {
+            eprintln!("Code failed, fix attempt {}/{}: {}", attempt, options.heal_attempts, error);
+        }
+        *response = client(llm_client, options, apis)?.fix_error(query, response, requested, &error, options.debug)?;
+    }
+}
+
+/// Recognises the `[{error: ..., message: ...}]` row the prompt tells generated code to return
+/// when it can't fetch the data, unless the user asked for an `error` column.
+fn error_row(rows: &[JsRow], requested: &[(String, ColumnType)]) -> Option<String> {
+    let [row] = rows else {
+        return None;
+    };
+    if requested.iter().any(|(name, _)| name == "error") {
+        return None;
+    }
+    let error = row.get("error").and_then(to_varchar)?;
+    match row.get("message").and_then(to_varchar) {
+        Some(message) => Some(format!("{}: {}", error, message)),
+        None => Some(error),
+    }
+}
+
+/// How many of the actual rows are shown to the LLM when asking it to repair code.
+const REPAIR_SAMPLE_ROWS: usize = 5;
+
+/// `name type, ...` for a `columns` override, as stored in the cache key.
+fn describe_columns(columns: &[(String, ColumnType)]) -> String {
+    columns
+        .iter()
+        .map(|(name, col_type)| format!("{} {}", name, col_type))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[repr(C)]
 struct JsBindData {
     code: String,
-    data: Vec<HashMap<String, JsValue>>,
+    columns: Vec<(String, ColumnType)>,
+    data: Vec<JsRow>,
 }
 
 #[repr(C)]
@@ -251,26 +364,31 @@ impl VTab for JsVTab {
 
     fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
         let code = bind.get_parameter(0).to_string();
+        let result_ttl = named_result_ttl(bind)?;
+        let sample_size = match named_int64(bind, "sample_size") {
+            Some(n) if n <= 0 => return Err(format!("sample_size must be positive, got {}", n).into()),
+            Some(n) => n as usize,
+            None => usize::MAX,
+        };
         
         // Execute the JavaScript code
-        let executor = JsExecutor::new();
-        let data = executor.execute_code(&code, false)?;
+        let executor = JsExecutor::new().with_secrets(secrets::http_secrets()).with_cassette(cassette::current()?);
+        let data = result_cache::execute_cached(&executor, &code, result_ttl, false, |_| true, false)?;
         
-        // Infer schema from first row if any
-        if let Some(first_row) = data.first() {
-            for (col_name, value) in first_row {
-                let logical_type = match value {
-                    JsValue::String(_) => LogicalTypeId::Varchar,
-                    JsValue::Float(_) => LogicalTypeId::Double,
-                    JsValue::Integer(_) => LogicalTypeId::Bigint,
-                    JsValue::Boolean(_) => LogicalTypeId::Boolean,
-                    JsValue::Null => LogicalTypeId::Varchar,
-                };
-                bind.add_result_column(col_name, LogicalTypeHandle::from(logical_type));
+        // Use the declared columns if given; otherwise infer the schema from every row (or the
+        // first sample_size rows), keeping columns in the order their keys first appear
+        let columns = match named_columns(bind)? {
+            Some(columns) => {
+                check_rows(&data, &columns)?;
+                columns
             }
+            None => infer_columns(&data[..data.len().min(sample_size)]),
+        };
+        for (col_name, col_type) in &columns {
+            bind.add_result_column(col_name, col_type.logical_type());
         }
         
-        Ok(JsBindData { code, data })
+        Ok(JsBindData { code, columns, data })
     }
 
     fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
@@ -293,41 +411,10 @@ impl VTab for JsVTab {
         let chunk_size = std::cmp::min(2048, bind_data.data.len() - current_row);
         let end_row = current_row + chunk_size;
         
-        // Get column names from the first row
-        if let Some(first_row) = bind_data.data.first() {
-            let columns: Vec<_> = first_row.keys().collect();
-            
-            for (col_idx, col_name) in columns.iter().enumerate() {
-                for (chunk_idx, row_idx) in (current_row..end_row).enumerate() {
-                    let row = &bind_data.data[row_idx];
-                    if let Some(value) = row.get(*col_name) {
-                        match value {
-                            JsValue::String(s) => {
-                                let c_str = CString::new(s.as_str())?;
-                                output.flat_vector(col_idx).insert(chunk_idx, c_str);
-                            },
-                            JsValue::Float(f) => {
-                                let mut vec = output.flat_vector(col_idx);
-                                let slice = vec.as_mut_slice::<f64>();
-                                slice[chunk_idx] = *f;
-                            },
-                            JsValue::Integer(i) => {
-                                let mut vec = output.flat_vector(col_idx);
-                                let slice = vec.as_mut_slice::<i64>();
-                                slice[chunk_idx] = *i;
-                            },
-                            JsValue::Boolean(b) => {
-                                let c_str = CString::new(b.to_string())?;
-                                output.flat_vector(col_idx).insert(chunk_idx, c_str);
-                            },
-                            JsValue::Null => {
-                                let c_str = CString::new("")?;
-                                output.flat_vector(col_idx).insert(chunk_idx, c_str);
-                            },
-                        }
-                    }
-                }
-            }
+        let rows = &bind_data.data[current_row..end_row];
+        for (col_idx, (col_name, col_type)) in bind_data.columns.iter().enumerate() {
+            let values: Vec<Option<&JsValue>> = rows.iter().map(|row| row.get(col_name)).collect();
+            write_column(output, col_idx, col_type, &values)?;
         }
         
         output.set_len(chunk_size);
@@ -339,6 +426,14 @@ impl VTab for JsVTab {
     fn parameters() -> Option<Vec<LogicalTypeHandle>> {
         Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
     }
+
+    fn named_parameters() -> Option<Vec<(String, LogicalTypeHandle)>> {
+        Some(vec![
+            ("result_ttl".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("sample_size".to_string(), LogicalTypeHandle::from(LogicalTypeId::Bigint)),
+            ("columns".to_string(), any_logical_type()),
+        ])
+    }
 }
 
 #[duckdb_entrypoint_c_api()]
@@ -349,5 +444,27 @@ pub unsafe fn extension_entrypoint(con: Connection) -> Result<(), Box<dyn Error>
         .expect("Failed to register wiz table function");
     con.register_table_function::<JsVTab>("js")
         .expect("Failed to register js table function");
+    con.register_table_function::<WizardSetVTab>("wizard_set")
+        .expect("Failed to register wizard_set table function");
+    con.register_table_function::<WizardCreateSecretVTab>("wizard_create_secret")
+        .expect("Failed to register wizard_create_secret table function");
+    con.register_table_function::<WizardDropSecretVTab>("wizard_drop_secret")
+        .expect("Failed to register wizard_drop_secret table function");
+    con.register_table_function::<WizardSecretsVTab>("wizard_secrets")
+        .expect("Failed to register wizard_secrets table function");
+    con.register_table_function::<WizardRegisterApiVTab>("wizard_register_api")
+        .expect("Failed to register wizard_register_api table function");
+    con.register_table_function::<WizardUnregisterApiVTab>("wizard_unregister_api")
+        .expect("Failed to register wizard_unregister_api table function");
+    con.register_table_function::<WizardApisVTab>("wizard_apis")
+        .expect("Failed to register wizard_apis table function");
+    con.register_table_function::<WizardCacheVTab>("wizard_cache")
+        .expect("Failed to register wizard_cache table function");
+    con.register_table_function::<WizardCacheEvictVTab>("wizard_cache_evict")
+        .expect("Failed to register wizard_cache_evict table function");
+    con.register_table_function::<WizardCacheClearVTab>("wizard_cache_clear")
+        .expect("Failed to register wizard_cache_clear table function");
+    con.register_table_function::<WizardCachePinVTab>("wizard_cache_pin")
+        .expect("Failed to register wizard_cache_pin table function");
     Ok(())
 }
\ No newline at end of file
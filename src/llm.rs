@@ -1,13 +1,16 @@
 use serde::{Deserialize, Serialize};
 use std::env;
-use async_openai::{
-    types::{CreateChatCompletionRequestArgs, ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs},
-    Client as OpenAIClient,
-};
-use misanthropy::{Anthropic, MessagesRequest, Message, Role, Content};
-use chrono;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::backend::{self, Capabilities, GenerateRequest, LlmBackend};
+use crate::cassette;
+use crate::catalog::ApiEntry;
+use crate::js_executor::JsRow;
+use crate::mock;
+use crate::prompt;
+use crate::providers::{self, DEFAULT_OPENAI_MODEL};
+use crate::settings;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LLMResponse {
     #[serde(alias = "python_code", alias = "javascript_code")]
     pub javascript_code: String,
@@ -17,85 +20,246 @@ pub struct LLMResponse {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ColumnSchema {
     pub name: String,
-    pub data_type: String, // "varchar", "double", "bigint", "date"
+    pub data_type: String, // e.g. "varchar", "double", "decimal(18,2)", "timestamp", "varchar[]", "struct", "map(varchar, double)"
+    /// Fields of a "struct" (or "struct[]") column.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<ColumnSchema>>,
 }
 
-pub enum LLMProvider {
-    OpenAI(String),
-    Anthropic(String),
+impl ColumnSchema {
+    pub fn new(name: &str, data_type: &str) -> Self {
+        ColumnSchema {
+            name: name.to_string(),
+            data_type: data_type.to_string(),
+            fields: None,
+        }
+    }
 }
 
-pub struct LLMClient {
-    provider: LLMProvider,
+/// Bump whenever the built-in prompt changes in a way that should invalidate cached code.
+pub const PROMPT_VERSION: u32 = 7;
+
+/// Returns the provider name `LLMClient::new` will use, without requiring its API key: the
+/// requested provider, else the `wizard_provider` setting, else the first configured backend.
+pub fn resolve_provider(provider: Option<&str>) -> String {
+    if let Some(p) = provider.map(str::to_string).or_else(|| settings::get("wizard_provider")) {
+        return p.trim().to_lowercase();
+    }
+    backend::configured_backends().into_iter().next().unwrap_or_else(|| "openai".to_string())
 }
 
-impl LLMClient {
-    pub fn new() -> Result<Self, String> {
-        // In CI environment, allow creation without API keys
-        if env::var("CI").unwrap_or_default() == "true" {
-            return Ok(LLMClient {
-                provider: LLMProvider::OpenAI("ci-stub-key".to_string()),
-            });
-        }
-        
-        if let Ok(api_key) = env::var("OPENAI_API_KEY") {
-            Ok(LLMClient {
-                provider: LLMProvider::OpenAI(api_key),
-            })
-        } else if let Ok(api_key) = env::var("ANTHROPIC_API_KEY") {
-            Ok(LLMClient {
-                provider: LLMProvider::Anthropic(api_key),
-            })
-        } else {
-            Err("No API key found. Set OPENAI_API_KEY or ANTHROPIC_API_KEY".to_string())
+/// Whether to answer with canned responses instead of calling an LLM: in CI, unless an
+/// OpenAI-compatible server has been configured to test against.
+fn use_ci_stub() -> bool {
+    env::var("CI").unwrap_or_default() == "true" && providers::openai_base_url().is_none()
+}
+
+/// Returns the model name used for `provider`: the requested model, else the
+/// `wizard_<provider>_model` or `wizard_model` setting, else the backend's default.
+pub fn resolve_model(provider: &str, model: Option<&str>) -> String {
+    if let Some(m) = model {
+        return m.to_string();
+    }
+    if let Some(m) = settings::get(&format!("wizard_{}_model", provider)).or_else(|| settings::get("wizard_model")) {
+        return m;
+    }
+    match backend::factory(provider) {
+        Some(factory) => factory.default_model().to_string(),
+        None => DEFAULT_OPENAI_MODEL.to_string(),
+    }
+}
+
+/// Answers every prompt with the same rows, so CI runs without API keys or network access.
+struct CiStub {
+    model: String,
+}
+
+impl LlmBackend for CiStub {
+    fn name(&self) -> &str {
+        "stub"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            structured_output: true,
+            local: true,
         }
     }
-    
-    pub fn generate_data_fetch_code(&self, query: &str, debug: bool) -> Result<LLMResponse, Box<dyn std::error::Error>> {
-        // Check if running in CI environment
-        if env::var("CI").unwrap_or_default() == "true" {
-            if debug {
-                eprintln!("Running in CI environment, returning stub response");
-            }
-            return Ok(LLMResponse {
-                javascript_code: r#"async function fetch_data() {
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        if request.debug {
+            eprintln!("Running in CI environment, returning stub response");
+        }
+        Ok(LLMResponse {
+            javascript_code: r#"async function fetch_data() {
     return [
         { id: 1, message: "CI stub response", value: 42.0 },
         { id: 2, message: "Test data", value: 3.14 }
     ];
 }"#.to_string(),
-                schema: vec![
-                    ColumnSchema { name: "id".to_string(), data_type: "bigint".to_string() },
-                    ColumnSchema { name: "message".to_string(), data_type: "varchar".to_string() },
-                    ColumnSchema { name: "value".to_string(), data_type: "double".to_string() },
-                ],
+            schema: vec![
+                ColumnSchema::new("id", "bigint"),
+                ColumnSchema::new("message", "varchar"),
+                ColumnSchema::new("value", "double"),
+            ],
+        })
+    }
+}
+
+pub struct LLMClient {
+    backend: Box<dyn LlmBackend>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    apis: Vec<ApiEntry>,
+}
+
+impl LLMClient {
+    /// Creates a client for the requested provider (any registered backend, e.g. "openai",
+    /// "anthropic" or "ollama"), or the one chosen by `resolve_provider` when none is given.
+    pub fn new(provider: Option<&str>, model: Option<&str>) -> Result<Self, String> {
+        let provider_name = resolve_provider(provider);
+        let model = resolve_model(&provider_name, model);
+        let factory = backend::factory(&provider_name).ok_or_else(|| {
+            format!(
+                "Unknown provider '{}'. Supported providers: {}",
+                provider_name,
+                backend::backend_names().join(", ")
+            )
+        })?;
+
+        // Replays need no API keys, and answer even in CI
+        let current = cassette::current()?;
+        if let Some(replaying) = current.as_ref().filter(|c| c.mode == cassette::Mode::Replay) {
+            return Ok(LLMClient {
+                backend: replaying.replay(&provider_name, &model),
+                temperature: None,
+                max_tokens: None,
+                apis: Vec::new(),
             });
         }
+
+        // In CI environment, allow creation without API keys. The mock backend is meant for
+        // tests, so it answers even there.
+        let mut backend: Box<dyn LlmBackend> = if use_ci_stub() && provider_name != mock::NAME {
+            Box::new(CiStub { model })
+        } else {
+            factory.create(&model)?
+        };
+        if let Some(recording) = &current {
+            backend = recording.record(&provider_name, backend);
+        }
         
-        let prompt = self.build_prompt(query);
+        Ok(LLMClient {
+            backend,
+            temperature: None,
+            max_tokens: None,
+            apis: Vec::new(),
+        })
+    }
+    
+    /// Sampling temperature; the provider's default if `None`.
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+    
+    /// Limit on the length of the response; the provider's default (4096 for Anthropic) if `None`.
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+    
+    /// Catalog entries the prompt lists for the query, as chosen by `catalog::select`; the
+    /// built-in free APIs are listed as examples if there are none.
+    pub fn with_apis(mut self, apis: Vec<ApiEntry>) -> Self {
+        self.apis = apis;
+        self
+    }
+    
+    /// Asks the LLM for code answering `query`. When `columns` is given, the code must return
+    /// exactly those columns.
+    pub fn generate_data_fetch_code(&self, query: &str, columns: Option<&[ColumnSchema]>, debug: bool) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let prompt = self.build_prompt(query, columns)?;
         
         if debug {
             eprintln!("Calling LLM API with query: {}", query);
         }
         
-        // Create a tokio runtime for async operations
-        let rt = tokio::runtime::Runtime::new()?;
+        self.complete(&prompt, query, false, debug)
+    }
+    
+    /// Asks the LLM to fix `previous`, whose rows didn't match its schema (or the requested
+    /// `columns`). `problems` describes the mismatches and `sample` holds some of the actual rows.
+    pub fn repair_code(
+        &self,
+        query: &str,
+        previous: &LLMResponse,
+        columns: Option<&[ColumnSchema]>,
+        problems: &[String],
+        sample: &[JsRow],
+        debug: bool,
+    ) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let rows: Vec<serde_json::Value> = sample.iter().map(JsRow::to_json).collect();
+        let feedback = format!(
+            "Running the code returned rows that do not match the schema:\n{}\n\nSample of the rows it returned:\n{}",
+            problems.iter().map(|p| format!("- {}", p)).collect::<Vec<_>>().join("\n"),
+            serde_json::to_string_pretty(&rows)?
+        );
+        let prompt = self.build_revision_prompt(query, previous, columns, &feedback)?;
         
-        let result = match &self.provider {
-            LLMProvider::OpenAI(api_key) => {
-                if debug {
-                    eprintln!("Using OpenAI API");
-                }
-                rt.block_on(self.call_openai(api_key, &prompt, debug))
-            }
-            LLMProvider::Anthropic(api_key) => {
-                if debug {
-                    eprintln!("Using Anthropic API");
-                }
-                rt.block_on(self.call_anthropic(api_key, &prompt, debug))
-            }
+        if debug {
+            eprintln!("Asking the LLM to repair the code for query: {}", query);
+        }
+        
+        self.complete(&prompt, query, true, debug)
+    }
+    
+    /// Asks the LLM to fix `previous`, which threw `error` (the exception message and stack
+    /// trace) when it ran.
+    pub fn fix_error(
+        &self,
+        query: &str,
+        previous: &LLMResponse,
+        columns: Option<&[ColumnSchema]>,
+        error: &str,
+        debug: bool,
+    ) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let feedback = format!("Running the code failed with this error:\n{}", error);
+        let prompt = self.build_revision_prompt(query, previous, columns, &feedback)?;
+        
+        if debug {
+            eprintln!("Asking the LLM to fix the code for query: {}", query);
+        }
+        
+        self.complete(&prompt, query, true, debug)
+    }
+    
+    fn complete(&self, prompt: &str, query: &str, revision: bool, debug: bool) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        // Without structured output, only the prompt keeps the answer to bare JSON
+        let json_prompt;
+        let prompt = if self.backend.capabilities().structured_output {
+            prompt
+        } else {
+            json_prompt = format!("{}\n\nIMPORTANT: Return ONLY valid JSON matching the specified format.", prompt);
+            &json_prompt
         };
         
+        if debug {
+            eprintln!("Using backend '{}' with model {}", self.backend.name(), self.backend.model());
+        }
+        let result = self.backend.generate(&GenerateRequest {
+            prompt,
+            query,
+            revision,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            debug,
+        });
+        
         if debug {
             match &result {
                 Ok(response) => {
@@ -112,178 +276,98 @@ impl LLMClient {
         result
     }
     
-    fn build_prompt(&self, query: &str) -> String {
-        let current_time = chrono::Local::now();
-        format!(
-            r#"You are a data wizard that helps fetch data based on natural language queries.
-
-Current date and time: {}
-
-User query: "{}"
-
-IMPORTANT: If the user asks for data with relative time periods (e.g., "last 7 days", "past week", "yesterday"), 
-calculate the dates based on the current date above. Do NOT use fixed dates.
+    /// The original prompt followed by the previous attempt and what went wrong with it.
+    fn build_revision_prompt(
+        &self,
+        query: &str,
+        previous: &LLMResponse,
+        columns: Option<&[ColumnSchema]>,
+        feedback: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(format!(
+            r#"{}
 
-Generate JavaScript code that fetches this data and returns it as an array of objects.
-Also provide the schema of the data that will be returned.
+A previous attempt produced this response:
+{}
 
-IMPORTANT RULES:
-1. The JavaScript code should define an async function called `fetch_data()` that returns an array of objects
-2. Each object represents a row of data
-3. Use the built-in fetch() function for HTTP requests (Deno has it built-in)
-4. You have access to all modern JavaScript/TypeScript features and Deno APIs
-5. Parse JSON responses with await response.json()
-6. You can import Node.js standard library modules using the "node:" prefix:
-   - import os from "node:os"
-   - import fs from "node:fs"
-   - import path from "node:path"
-   - import crypto from "node:crypto"
-7. You can import npm packages dynamically using esm.sh CDN:
-   - For date formatting: import dayjs from "https://esm.sh/dayjs@1.11.10"
-   - For data processing: import lodash from "https://esm.sh/lodash@4.17.21"
-   - For any npm package: import packageName from "https://esm.sh/package-name@version"
-   - This provides instant access to thousands of npm packages without installation
-8. For Yahoo Finance API specifically:
-   - The endpoint https://query1.finance.yahoo.com/v8/finance/chart/SYMBOL returns detailed data
-   - Extract current price from: result.chart.result[0].meta.regularMarketPrice
-   - Extract symbol from: result.chart.result[0].meta.symbol
-9. ALWAYS use REAL, FREE APIs. NEVER make up API endpoints. Examples of real free APIs:
-   - Weather: wttr.in (e.g., await fetch('https://wttr.in/Seattle?format=j1'))
-   - Earthquakes: USGS (e.g., await fetch('https://earthquake.usgs.gov/earthquakes/feed/v1.0/summary/all_month.geojson'))
-   - Crypto: CoinGecko free tier (e.g., await fetch('https://api.coingecko.com/api/v3/simple/price?ids=bitcoin&vs_currencies=usd'))
-   - IP info: await fetch('https://ipapi.co/json/')
-   - Jokes: await fetch('https://official-joke-api.appspot.com/random_joke')
-10. If no suitable FREE API exists for the query, return an error:
-   return [{{error: 'No free API available', message: 'Cannot find a free API for: [query description]'}}]
-11. If API call fails, return the error details:
-   try {{
-       const response = await fetch(url);
-       const data = await response.json();
-       // process data
-   }} catch (e) {{
-       return [{{error: 'API call failed', message: e.message, url: url}}];
-   }}
-12. NEVER return empty array [] - always return error details if something goes wrong
-13. IMPORTANT: Include ALL relevant columns that make sense for the query:
-    - For weather: include date, location, temperature, conditions, humidity, wind, etc.
-    - For earthquakes: include magnitude, place, time, depth, coordinates, etc.
-    - For crypto: include coin name, symbol, price, market cap, 24h change if available
-    - Always prefer more complete data over minimal responses
-14. The schema should match the data exactly and include all columns you return
+{}
 
-Return your response as JSON in this exact format:
-{{
-    "javascript_code": "async function fetch_data() {{\n    // Your code here\n    return data;\n}}",
-    "schema": [
-        {{"name": "column1", "data_type": "varchar"}},
-        {{"name": "column2", "data_type": "double"}}
-    ]
-}}
-
-Supported data types: varchar, double, bigint, date
-
-Example for "recent earthquakes":
-{{
-    "javascript_code": "async function fetch_data() {{\n    const response = await fetch('https://earthquake.usgs.gov/earthquakes/feed/v1.0/summary/significant_month.geojson');\n    const data = await response.json();\n    \n    return data.features.map(feature => ({{\n        magnitude: feature.properties.mag,\n        place: feature.properties.place,\n        time: new Date(feature.properties.time).toISOString(),\n        depth: feature.geometry.coordinates[2],\n        latitude: feature.geometry.coordinates[1],\n        longitude: feature.geometry.coordinates[0],\n        type: feature.properties.type\n    }}));\n}}",
-    "schema": [
-        {{"name": "magnitude", "data_type": "double"}},
-        {{"name": "place", "data_type": "varchar"}},
-        {{"name": "time", "data_type": "varchar"}},
-        {{"name": "depth", "data_type": "double"}},
-        {{"name": "latitude", "data_type": "double"}},
-        {{"name": "longitude", "data_type": "double"}},
-        {{"name": "type", "data_type": "varchar"}}
-    ]
-}}"#,
-            current_time,
-            query
-        )
+Fix the code (or the schema, if the data is right and the schema is wrong) and return the complete corrected
+response in the same JSON format."#,
+            self.build_prompt(query, columns)?,
+            serde_json::to_string_pretty(previous)?,
+            feedback
+        ))
     }
     
-    async fn call_openai(&self, api_key: &str, prompt: &str, debug: bool) -> Result<LLMResponse, Box<dyn std::error::Error>> {
-        let client = OpenAIClient::with_config(
-            async_openai::config::OpenAIConfig::default()
-                .with_api_key(api_key)
-        );
-        
-        // Add JSON instruction to prompt for OpenAI
-        let json_prompt = format!("{}\n\nIMPORTANT: Return ONLY valid JSON matching the specified format.", prompt);
-        
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("gpt-4o")
-            .messages([
-                ChatCompletionRequestMessage::System(
-                    ChatCompletionRequestSystemMessageArgs::default()
-                        .content("You are a helpful assistant that generates JavaScript code and data schemas. Always respond with valid JSON.")
-                        .build()?
-                ),
-                ChatCompletionRequestMessage::User(
-                    ChatCompletionRequestUserMessageArgs::default()
-                        .content(json_prompt)
-                        .build()?
-                ),
-            ])
-            .build()?;
-        
-        let response = client.chat().create(request).await?;
-        let content = response.choices[0].message.content.as_ref()
-            .ok_or("No content in response")?;
-        
-        if debug {
-            eprintln!("Raw OpenAI response: {}", content);
-        }
-        
-        // Strip markdown code blocks if present
-        let cleaned_content = if content.starts_with("```json") && content.ends_with("```") {
-            content.strip_prefix("```json").unwrap()
-                .strip_suffix("```").unwrap()
-                .trim()
-        } else if content.starts_with("```") && content.ends_with("```") {
-            content.strip_prefix("```").unwrap()
-                .strip_suffix("```").unwrap()
-                .trim()
-        } else {
-            content
-        };
-        
-        serde_json::from_str(cleaned_content).map_err(|e| {
-            if debug {
-                eprintln!("JSON parse error: {}", e);
-                eprintln!("Attempted to parse: {}", cleaned_content);
+    fn build_prompt(&self, query: &str, columns: Option<&[ColumnSchema]>) -> Result<String, String> {
+        prompt::build(query, columns, &self.apis)
+    }
+}
+
+/// JSON schema of `LLMResponse`, for structured output. Every property is required (with
+/// `fields` nullable) so OpenAI's strict mode accepts it.
+pub fn response_json_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "javascript_code": {
+                "type": "string",
+                "description": "JavaScript defining async function fetch_data() that returns an array of objects"
+            },
+            "schema": {
+                "type": "array",
+                "items": { "$ref": "#/$defs/column" }
             }
-            e.into()
-        })
+        },
+        "required": ["javascript_code", "schema"],
+        "additionalProperties": false,
+        "$defs": {
+            "column": {
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string" },
+                    "data_type": { "type": "string" },
+                    "fields": {
+                        "anyOf": [
+                            { "type": "array", "items": { "$ref": "#/$defs/column" } },
+                            { "type": "null" }
+                        ]
+                    }
+                },
+                "required": ["name", "data_type", "fields"],
+                "additionalProperties": false
+            }
+        }
+    })
+}
+
+/// Parses the JSON object the LLM was asked to return. Falls back to the first JSON object in
+/// the text that has the expected shape, so preambles and code fences don't fail the query.
+pub fn parse_response(content: &str, debug: bool) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+    let content = content.trim();
+    let error = match serde_json::from_str(content) {
+        Ok(response) => return Ok(response),
+        Err(e) => e,
+    };
+    
+    if let Some(response) = extract_response(content) {
+        return Ok(response);
     }
     
-    async fn call_anthropic(&self, api_key: &str, prompt: &str, _debug: bool) -> Result<LLMResponse, Box<dyn std::error::Error>> {
-        let client = Anthropic::from_string_or_env(api_key)?;
-        
-        // Add explicit JSON instruction for Claude
-        let json_prompt = format!("{}\n\nIMPORTANT: Return ONLY valid JSON, no additional text.", prompt);
-        
-        let mut request = MessagesRequest::default();
-        request.model = "claude-3-haiku-20240307".to_string();
-        request.messages = vec![
-            Message {
-                role: Role::User,
-                content: vec![Content::text(json_prompt)],
-            }
-        ];
-        request.max_tokens = 4096;
-        
-        let response = client.messages(&request).await?;
-        
-        // Extract text from response
-        let text = response.content.iter()
-            .filter_map(|content| {
-                match content {
-                    Content::Text(text_content) => Some(text_content.text.as_str()),
-                    _ => None,
-                }
-            })
-            .next()
-            .ok_or("No text content in response")?;
-        
-        serde_json::from_str(text).map_err(|e| e.into())
+    if debug {
+        eprintln!("JSON parse error: {}", error);
+        eprintln!("Attempted to parse: {}", content);
     }
-}
\ No newline at end of file
+    Err(format!("Could not find a valid response in the LLM's answer: {}", error).into())
+}
+
+/// Finds the first `LLMResponse` object embedded in `text`.
+fn extract_response(text: &str) -> Option<LLMResponse> {
+    text.match_indices('{').find_map(|(start, _)| {
+        serde_json::Deserializer::from_str(&text[start..])
+            .into_iter::<LLMResponse>()
+            .next()
+            .and_then(Result::ok)
+    })
+}
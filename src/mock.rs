@@ -0,0 +1,158 @@
+//! A deterministic backend for tests and demos that answers prompts from fixture files instead
+//! of calling an LLM.
+//!
+//! `wizard_mock_fixtures` names a directory of `.json` files, each holding one fixture or an
+//! array of them:
+//!
+//! ```json
+//! {
+//!     "pattern": "weather in *",
+//!     "response": { "javascript_code": "async function fetch_data() { ... }", "schema": [...] },
+//!     "revision": { "javascript_code": "...", "schema": [...] }
+//! }
+//! ```
+//!
+//! `pattern` is matched against the whole query, ignoring case, with `*` matching any text.
+//! Files are read in name order and the first matching fixture answers. `revision`, if given,
+//! answers requests to fix code that threw or returned rows not matching its schema; otherwise
+//! those get `response` again.
+
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+use crate::backend::{BackendFactory, Capabilities, GenerateRequest, LlmBackend};
+use crate::llm::LLMResponse;
+use crate::settings;
+
+/// The name the mock backend is registered under.
+pub const NAME: &str = "mock";
+
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    pattern: String,
+    response: LLMResponse,
+    #[serde(default)]
+    revision: Option<LLMResponse>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FixtureFile {
+    One(Fixture),
+    Many(Vec<Fixture>),
+}
+
+fn fixtures_dir() -> Option<PathBuf> {
+    settings::get("wizard_mock_fixtures").map(PathBuf::from)
+}
+
+/// Reads every fixture in `dir`, in file name order.
+fn load_fixtures(dir: &PathBuf) -> Result<Vec<Fixture>, Box<dyn std::error::Error>> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Could not read mock fixtures from '{}': {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let mut fixtures = Vec::new();
+    for path in paths {
+        let content = fs::read_to_string(&path)?;
+        let file: FixtureFile = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid mock fixture '{}': {}", path.display(), e))?;
+        match file {
+            FixtureFile::One(fixture) => fixtures.push(fixture),
+            FixtureFile::Many(many) => fixtures.extend(many),
+        }
+    }
+    Ok(fixtures)
+}
+
+/// Whether `text` matches `pattern` as a whole, ignoring case, with `*` matching any text.
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: the whole query must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+pub struct MockFactory;
+
+impl BackendFactory for MockFactory {
+    fn default_model(&self) -> &str {
+        "fixtures"
+    }
+
+    /// Never picked automatically, so setting up fixtures doesn't take over queries meant for a
+    /// real provider; choose it with `provider := 'mock'` or `wizard_provider`.
+    fn is_configured(&self) -> bool {
+        false
+    }
+
+    fn create(&self, model: &str) -> Result<Box<dyn LlmBackend>, String> {
+        let dir = fixtures_dir().ok_or(
+            "provider 'mock' requires wizard_mock_fixtures, a directory of fixture files. Set it with \
+             CALL wizard_set('wizard_mock_fixtures', 'path/to/fixtures')",
+        )?;
+        Ok(Box::new(MockBackend {
+            dir,
+            model: model.to_string(),
+        }))
+    }
+}
+
+pub struct MockBackend {
+    dir: PathBuf,
+    model: String,
+}
+
+impl LlmBackend for MockBackend {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            structured_output: true,
+            local: true,
+        }
+    }
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        // Fixtures are read on every request, so edits apply without reloading the extension
+        let fixture = load_fixtures(&self.dir)?
+            .into_iter()
+            .find(|fixture| matches(&fixture.pattern, request.query))
+            .ok_or_else(|| {
+                format!("No mock fixture matches query '{}' in '{}'", request.query, self.dir.display())
+            })?;
+
+        if request.debug {
+            eprintln!("Answering from mock fixture '{}'", fixture.pattern);
+        }
+        match fixture.revision {
+            Some(revision) if request.revision => Ok(revision),
+            _ => Ok(fixture.response),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use duckdb::{
+    core::{LogicalTypeHandle, LogicalTypeId},
+    vtab::BindInfo,
+};
+use std::time::Duration;
+
+use crate::cache::CacheTtl;
+use crate::llm;
+use crate::providers::ANTHROPIC_MAX_TEMPERATURE;
+use crate::result_cache;
+use crate::settings;
+use crate::types::{any_logical_type, parse_column_spec, ColumnType};
+
+/// Options accepted by `wizard()` / `wiz()` as DuckDB named parameters, e.g.
+/// `SELECT * FROM wizard('bitcoin price', debug := true, max_rows := 10)`.
+#[derive(Debug, Clone, Default)]
+pub struct WizardOptions {
+    pub debug: bool,
+    pub refresh: bool,
+    pub model: Option<String>,
+    pub provider: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub max_rows: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub ttl: Option<CacheTtl>,
+    pub pin: bool,
+    pub result_ttl: Option<Duration>,
+    pub columns: Option<Vec<(String, ColumnType)>>,
+    pub repair_attempts: usize,
+    pub heal_attempts: usize,
+    pub on_error: OnError,
+}
+
+/// What `wizard()` does when the generated code reports a failure with the
+/// `[{error: ..., message: ...}]` row the prompt asks for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Fail the query with the reported message
+    #[default]
+    Raise,
+    /// Return the error row as-is
+    Row,
+    /// Return no rows
+    Empty,
+}
+
+impl OnError {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "raise" => Ok(OnError::Raise),
+            "row" => Ok(OnError::Row),
+            "empty" => Ok(OnError::Empty),
+            _ => Err(format!("on_error must be 'raise', 'row' or 'empty', got '{}'", value)),
+        }
+    }
+}
+
+/// How many times `wizard()` asks the LLM to fix code whose rows don't match the schema,
+/// unless `repair_attempts` or `wizard_repair_attempts` says otherwise.
+pub const DEFAULT_REPAIR_ATTEMPTS: usize = 2;
+
+impl WizardOptions {
+    pub fn named_parameters() -> Vec<(String, LogicalTypeHandle)> {
+        vec![
+            ("debug".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean)),
+            ("refresh".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean)),
+            ("model".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("provider".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("temperature".to_string(), LogicalTypeHandle::from(LogicalTypeId::Double)),
+            ("max_tokens".to_string(), LogicalTypeHandle::from(LogicalTypeId::Bigint)),
+            ("max_rows".to_string(), LogicalTypeHandle::from(LogicalTypeId::Bigint)),
+            ("timeout".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("ttl".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("pin".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean)),
+            ("result_ttl".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("columns".to_string(), any_logical_type()),
+            ("repair_attempts".to_string(), LogicalTypeHandle::from(LogicalTypeId::Bigint)),
+            ("heal_attempts".to_string(), LogicalTypeHandle::from(LogicalTypeId::Bigint)),
+            ("on_error".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+        ]
+    }
+
+    pub fn from_bind(bind: &BindInfo) -> Result<Self, Box<dyn std::error::Error>> {
+        let max_rows = match named_int64(bind, "max_rows") {
+            Some(n) if n < 0 => return Err(format!("max_rows must be non-negative, got {}", n).into()),
+            Some(n) => Some(n as usize),
+            None => None,
+        };
+
+        let provider = named_varchar(bind, "provider");
+        let temperature = match named_varchar(bind, "temperature").or_else(|| settings::get("wizard_temperature")) {
+            Some(s) => {
+                // The range depends on the provider the query will run with
+                let (max, range) = match llm::resolve_provider(provider.as_deref()).as_str() {
+                    "anthropic" => (ANTHROPIC_MAX_TEMPERATURE, "between 0 and 1 for anthropic"),
+                    _ => (2.0, "between 0 and 2"),
+                };
+                match s.trim().parse::<f32>() {
+                    Ok(t) if (0.0..=max).contains(&t) => Some(t),
+                    _ => return Err(format!("temperature must be a number {}, got '{}'", range, s).into()),
+                }
+            }
+            None => None,
+        };
+
+        let max_tokens = match named_int64(bind, "max_tokens") {
+            Some(n) => Some(n.to_string()),
+            None => settings::get("wizard_max_tokens"),
+        };
+        let max_tokens = match max_tokens {
+            Some(s) => match s.trim().parse::<u32>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err(format!("max_tokens must be a positive number, got '{}'", s).into()),
+            },
+            None => None,
+        };
+
+        let timeout = match named_varchar(bind, "timeout") {
+            Some(s) => Some(parse_duration(&s)?),
+            None => None,
+        };
+
+        let ttl = match named_varchar(bind, "ttl") {
+            Some(s) => Some(CacheTtl::parse(&s)?),
+            None => None,
+        };
+
+        let result_ttl = named_result_ttl(bind)?;
+
+        let repair_attempts = named_attempts(bind, "repair_attempts", DEFAULT_REPAIR_ATTEMPTS)?;
+        let heal_attempts = named_attempts(bind, "heal_attempts", 0)?;
+
+        let on_error = match named_varchar(bind, "on_error") {
+            Some(s) => OnError::parse(&s)?,
+            None => OnError::default(),
+        };
+
+        Ok(WizardOptions {
+            debug: named_bool(bind, "debug").unwrap_or(false),
+            refresh: named_bool(bind, "refresh").unwrap_or(false),
+            model: named_varchar(bind, "model"),
+            provider,
+            temperature,
+            max_tokens,
+            max_rows,
+            timeout,
+            ttl,
+            pin: named_bool(bind, "pin").unwrap_or(false),
+            result_ttl,
+            columns: named_columns(bind)?,
+            repair_attempts,
+            heal_attempts,
+            on_error,
+        })
+    }
+}
+
+/// A retry count such as `repair_attempts`, falling back to the `wizard_`-prefixed setting.
+fn named_attempts(bind: &BindInfo, name: &str, default: usize) -> Result<usize, Box<dyn std::error::Error>> {
+    match named_int64(bind, name) {
+        Some(n) if n < 0 => Err(format!("{} must be non-negative, got {}", name, n).into()),
+        Some(n) => Ok(n as usize),
+        None => {
+            let setting = format!("wizard_{}", name);
+            match settings::get(&setting) {
+                Some(value) => Ok(value.trim().parse().map_err(|_| {
+                    format!("{}: expected a non-negative number, got '{}'", setting, value)
+                })?),
+                None => Ok(default),
+            }
+        }
+    }
+}
+
+/// The `columns` named parameter, e.g. `columns := {'price': 'DOUBLE', 'ts': 'TIMESTAMP'}`.
+pub fn named_columns(bind: &BindInfo) -> Result<Option<Vec<(String, ColumnType)>>, Box<dyn std::error::Error>> {
+    match named_varchar(bind, "columns") {
+        Some(spec) => Ok(Some(parse_column_spec(&spec).map_err(|e| format!("columns: {}", e))?)),
+        None => Ok(None),
+    }
+}
+
+/// The `result_ttl` named parameter, falling back to the `wizard_result_ttl` setting.
+/// A zero TTL disables result caching.
+pub fn named_result_ttl(bind: &BindInfo) -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+    match named_varchar(bind, "result_ttl") {
+        Some(s) => Ok(Some(parse_duration(&s)?).filter(|ttl| !ttl.is_zero())),
+        None => Ok(result_cache::ttl_from_settings()?),
+    }
+}
+
+pub fn named_varchar(bind: &BindInfo, name: &str) -> Option<String> {
+    bind.get_named_parameter(name)
+        .map(|v| v.to_string())
+        .filter(|s| !s.is_empty())
+}
+
+pub fn named_bool(bind: &BindInfo, name: &str) -> Option<bool> {
+    bind.get_named_parameter(name)
+        .map(|v| v.to_string().eq_ignore_ascii_case("true"))
+}
+
+pub fn named_int64(bind: &BindInfo, name: &str) -> Option<i64> {
+    bind.get_named_parameter(name).map(|v| v.to_int64())
+}
+
+/// Parses durations such as `30s`, `500ms`, `2m`, `1h` or a bare number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", value))?;
+
+    let seconds = match unit.trim() {
+        "" | "s" | "sec" | "secs" | "second" | "seconds" => number,
+        "ms" => number / 1000.0,
+        "m" | "min" | "mins" | "minute" | "minutes" => number * 60.0,
+        "h" | "hour" | "hours" => number * 3600.0,
+        "d" | "day" | "days" => number * 86400.0,
+        other => return Err(format!("Unknown duration unit '{}' in '{}'", other, value)),
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Invalid duration '{}': out of range", value))
+}
@@ -0,0 +1,255 @@
+//! The prompt sent to the LLM, built from a template with `{{placeholder}}`s.
+//!
+//! The built-in template can be replaced with the `wizard_prompt_template` setting (the template
+//! itself) or `wizard_prompt_template_file` (a file holding it), and `wizard_prompt_rules` adds
+//! organisation-specific rules to whichever template is used.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+};
+
+use crate::catalog::{self, ApiEntry};
+use crate::llm::ColumnSchema;
+use crate::secrets;
+use crate::settings;
+
+/// Placeholders a template can use, each replaced by a section of the prompt. The sections
+/// other than `date` and `query` start with a newline and are empty when there is nothing to say.
+pub const PLACEHOLDERS: &[(&str, &str)] = &[
+    ("date", "the current date and time"),
+    ("query", "the user's query"),
+    ("columns", "the columns the user requires, if any"),
+    ("secrets", "the API keys available to the code in WIZARD_SECRETS"),
+    ("catalog", "the APIs from the catalog that fit the query"),
+    ("rules", "the rules from wizard_prompt_rules"),
+    ("format", "the JSON response format and supported data types; appended if the template doesn't use it"),
+];
+
+pub const DEFAULT_TEMPLATE: &str = r#"You are a data wizard that helps fetch data based on natural language queries.
+
+Current date and time: {{date}}
+
+User query: "{{query}}"
+{{columns}}{{secrets}}
+IMPORTANT: If the user asks for data with relative time periods (e.g., "last 7 days", "past week", "yesterday"), 
+calculate the dates based on the current date above. Do NOT use fixed dates.
+
+Generate JavaScript code that fetches this data and returns it as an array of objects.
+Also provide the schema of the data that will be returned.
+
+IMPORTANT RULES:
+1. The JavaScript code should define an async function called `fetch_data()` that returns an array of objects
+2. Each object represents a row of data
+3. Use the built-in fetch() function for HTTP requests (Deno has it built-in)
+4. You have access to all modern JavaScript/TypeScript features and Deno APIs
+5. Parse JSON responses with await response.json()
+6. You can import Node.js standard library modules using the "node:" prefix:
+   - import os from "node:os"
+   - import fs from "node:fs"
+   - import path from "node:path"
+   - import crypto from "node:crypto"
+7. You can import npm packages dynamically using esm.sh CDN:
+   - For date formatting: import dayjs from "https://esm.sh/dayjs@1.11.10"
+   - For data processing: import lodash from "https://esm.sh/lodash@4.17.21"
+   - For any npm package: import packageName from "https://esm.sh/package-name@version"
+   - This provides instant access to thousands of npm packages without installation
+8. ALWAYS use REAL APIs. NEVER make up API endpoints. Prefer the APIs listed below when they fit the query.
+9. If no suitable FREE API exists for the query, return an error:
+   return [{error: 'No free API available', message: 'Cannot find a free API for: [query description]'}]
+10. If API call fails, return the error details:
+   try {
+       const response = await fetch(url);
+       const data = await response.json();
+       // process data
+   } catch (e) {
+       return [{error: 'API call failed', message: e.message, url: url}];
+   }
+11. NEVER return empty array [] - always return error details if something goes wrong
+12. IMPORTANT: Include ALL relevant columns that make sense for the query:
+    - For weather: include date, location, temperature, conditions, humidity, wind, etc.
+    - For earthquakes: include magnitude, place, time, depth, coordinates, etc.
+    - For crypto: include coin name, symbol, price, market cap, 24h change if available
+    - Always prefer more complete data over minimal responses
+13. The schema should match the data exactly and include all columns you return
+{{rules}}{{catalog}}
+{{format}}"#;
+
+/// How the answer must be shaped. Always part of the prompt, since the response is parsed.
+const RESPONSE_FORMAT: &str = r#"Return your response as JSON in this exact format:
+{
+    "javascript_code": "async function fetch_data() {\n    // Your code here\n    return data;\n}",
+    "schema": [
+        {"name": "column1", "data_type": "varchar"},
+        {"name": "column2", "data_type": "double"}
+    ]
+}
+
+Supported data types:
+- varchar
+- boolean
+- tinyint, smallint, integer, bigint (whole numbers of increasing width)
+- float, double
+- decimal(precision,scale), e.g. decimal(18,2) for money
+- date (return "YYYY-MM-DD" strings)
+- time (return "HH:MM:SS" strings)
+- timestamp (return ISO-8601 strings such as "2024-05-01T12:30:00", or epoch milliseconds)
+- timestamptz (return ISO-8601 strings with an offset such as "2024-05-01T12:30:00Z", or epoch milliseconds)
+- lists: append [] to any type, e.g. varchar[] for an array of tags or double[] for coordinates
+- structs: struct(name type, ...), e.g. struct(lat double, lon double) for a nested object
+- maps: map(key_type, value_type), e.g. map(varchar, double) for objects with arbitrary keys
+- json: semi-structured values whose shape varies or is too deep to describe; return them as plain objects/arrays
+Prefer date/timestamp/timestamptz over varchar for dates and times, and boolean over varchar for flags.
+Return nested arrays and objects as they are instead of stringifying them, and describe them with list, struct or map types.
+
+Example for "recent earthquakes":
+{
+    "javascript_code": "async function fetch_data() {\n    const response = await fetch('https://earthquake.usgs.gov/earthquakes/feed/v1.0/summary/significant_month.geojson');\n    const data = await response.json();\n    \n    return data.features.map(feature => ({\n        magnitude: feature.properties.mag,\n        place: feature.properties.place,\n        time: new Date(feature.properties.time).toISOString(),\n        depth: feature.geometry.coordinates[2],\n        latitude: feature.geometry.coordinates[1],\n        longitude: feature.geometry.coordinates[0],\n        type: feature.properties.type\n    }));\n}",
+    "schema": [
+        {"name": "magnitude", "data_type": "double"},
+        {"name": "place", "data_type": "varchar"},
+        {"name": "time", "data_type": "timestamptz"},
+        {"name": "depth", "data_type": "double"},
+        {"name": "latitude", "data_type": "double"},
+        {"name": "longitude", "data_type": "double"},
+        {"name": "type", "data_type": "varchar"}
+    ]
+}"#;
+
+/// The template in use: `wizard_prompt_template`, else the contents of
+/// `wizard_prompt_template_file`, else the built-in one.
+pub fn template() -> Result<String, String> {
+    if let Some(template) = settings::get("wizard_prompt_template") {
+        return Ok(template);
+    }
+    match settings::get("wizard_prompt_template_file") {
+        Some(path) => fs::read_to_string(&path).map_err(|e| format!("Could not read prompt template '{}': {}", path, e)),
+        None => Ok(DEFAULT_TEMPLATE.to_string()),
+    }
+}
+
+/// Identifies a customised template, rules, catalog or set of API keys, so code generated from
+/// another prompt isn't reused from the cache. `apis` holds the catalog entries selected for
+/// the query. Empty for the built-in prompt.
+pub fn fingerprint(apis: &[ApiEntry]) -> Result<String, String> {
+    let template = template()?;
+    let rules = settings::get("wizard_prompt_rules");
+    let secrets = secrets_section();
+    let custom_catalog = catalog::customises_prompt(apis);
+    if template == DEFAULT_TEMPLATE && rules.is_none() && secrets.is_empty() && !custom_catalog {
+        return Ok(String::new());
+    }
+    let mut hasher = DefaultHasher::new();
+    template.hash(&mut hasher);
+    rules.hash(&mut hasher);
+    secrets.hash(&mut hasher);
+    if custom_catalog {
+        catalog::prompt_section(apis).hash(&mut hasher);
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Renders the prompt for `query`, which must return exactly `columns` if given, listing the
+/// catalog entries `apis` selected for it.
+pub fn build(query: &str, columns: Option<&[ColumnSchema]>, apis: &[ApiEntry]) -> Result<String, String> {
+    let template = template()?;
+    let sections = [
+        ("date", chrono::Local::now().to_string()),
+        ("query", query.to_string()),
+        ("columns", columns_section(columns)),
+        ("secrets", secrets_section()),
+        ("catalog", catalog::prompt_section(apis)),
+        ("rules", rules_section()),
+        ("format", RESPONSE_FORMAT.to_string()),
+    ];
+    let mut prompt = render(&template, &sections)?;
+    if !uses(&template, "rules") {
+        prompt.push_str(&rules_section());
+    }
+    if !uses(&template, "format") {
+        prompt.push_str("\n\n");
+        prompt.push_str(RESPONSE_FORMAT);
+    }
+    Ok(prompt)
+}
+
+fn uses(template: &str, placeholder: &str) -> bool {
+    template.contains(&format!("{{{{{}}}}}", placeholder))
+}
+
+/// Replaces every `{{name}}` in `template`. Unknown names are an error, so typos don't silently
+/// reach the LLM.
+fn render(template: &str, sections: &[(&str, String)]) -> Result<String, String> {
+    let mut prompt = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        prompt.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let name = after
+            .find("}}")
+            .map(|end| &after[..end])
+            .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        match name {
+            Some(name) => {
+                let value = sections.iter().find(|(n, _)| *n == name).map(|(_, v)| v).ok_or_else(|| {
+                    let known: Vec<&str> = PLACEHOLDERS.iter().map(|(n, _)| *n).collect();
+                    format!("Unknown placeholder '{{{{{}}}}}' in the prompt template. Available placeholders: {}", name, known.join(", "))
+                })?;
+                prompt.push_str(value);
+                rest = &after[name.len() + 2..];
+            }
+            // Not a placeholder, e.g. JavaScript braces
+            None => {
+                prompt.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    prompt.push_str(rest);
+    Ok(prompt)
+}
+
+fn columns_section(columns: Option<&[ColumnSchema]>) -> String {
+    let Some(columns) = columns else {
+        return String::new();
+    };
+    let list: Vec<String> = columns
+        .iter()
+        .map(|c| format!("- {} ({})", c.name, c.data_type))
+        .collect();
+    format!(
+        "\nThe user requires exactly these output columns, in this order. Every returned object must use \
+         these keys with values of these types, and the schema you return must list exactly these columns:\n{}\n",
+        list.join("\n")
+    )
+}
+
+fn secrets_section() -> String {
+    let api_keys = secrets::http_secrets();
+    if api_keys.is_empty() {
+        return String::new();
+    }
+    let list: Vec<String> = api_keys
+        .iter()
+        .map(|s| format!("- WIZARD_SECRETS[\"{}\"].key for requests to {}", s.name, s.scope.as_deref().unwrap_or("")))
+        .collect();
+    format!(
+        "\nAPI keys are available in the global WIZARD_SECRETS object. Each key is a placeholder that fetch() \
+         replaces with the real key in the URL, headers or a string body of requests under its scope, and \
+         requests elsewhere fail. Use the keys unchanged (not encoded or combined with anything else into a \
+         new string that fetch() can't recognise), prefer these APIs when they fit the query, and never \
+         hard-code, log or return the keys:\n{}\n",
+        list.join("\n")
+    )
+}
+
+fn rules_section() -> String {
+    match settings::get("wizard_prompt_rules") {
+        Some(rules) => format!(
+            "\nAdditional rules from the user's organisation. They take precedence over the rules above:\n{}\n",
+            rules.trim_end()
+        ),
+        None => String::new(),
+    }
+}
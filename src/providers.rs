@@ -0,0 +1,538 @@
+//! The built-in LLM backends: OpenAI (and OpenAI-compatible servers), Anthropic and Ollama.
+
+use async_openai::{
+    types::{
+        CreateChatCompletionRequestArgs, ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestUserMessageArgs, ResponseFormat, ResponseFormatJsonSchema,
+    },
+    Client as OpenAIClient,
+};
+use std::env;
+
+use crate::backend::{self, BackendFactory, Capabilities, GenerateRequest, LlmBackend};
+use crate::llm::{parse_response, response_json_schema, LLMResponse};
+use crate::secrets::{self, SecretType};
+use crate::settings;
+
+pub const DEFAULT_OPENAI_MODEL: &str = "gpt-4o";
+pub const DEFAULT_ANTHROPIC_MODEL: &str = "claude-3-haiku-20240307";
+pub const DEFAULT_OLLAMA_MODEL: &str = "llama3.1";
+pub const DEFAULT_OLLAMA_HOST: &str = "http://localhost:11434";
+/// Anthropic only accepts temperatures up to 1; OpenAI and Ollama accept up to 2.
+pub const ANTHROPIC_MAX_TEMPERATURE: f32 = 1.0;
+
+const SYSTEM_PROMPT: &str =
+    "You are a helpful assistant that generates JavaScript code and data schemas. Always respond with valid JSON.";
+
+/// Name of the tool Anthropic models call to return their response.
+const RESPONSE_TOOL_NAME: &str = "return_data_fetch_code";
+
+/// The OpenAI-compatible server to send requests to instead of api.openai.com (vLLM, llama.cpp,
+/// Ollama's `/v1` endpoint, LiteLLM...), from `wizard_openai_base_url` or `OPENAI_BASE_URL`.
+pub fn openai_base_url() -> Option<String> {
+    settings::get("wizard_openai_base_url")
+        .or_else(|| secrets::find(SecretType::OpenAICompatible).and_then(|s| s.base_url))
+        .or_else(|| env::var("OPENAI_BASE_URL").ok().filter(|v| !v.is_empty()))
+}
+
+/// The Ollama daemon to talk to, from `wizard_ollama_host` or `OLLAMA_HOST`.
+pub fn ollama_host() -> String {
+    let host = settings::get("wizard_ollama_host")
+        .or_else(|| env::var("OLLAMA_HOST").ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| DEFAULT_OLLAMA_HOST.to_string());
+    // OLLAMA_HOST is often just host:port
+    let host = if host.contains("://") { host } else { format!("http://{}", host) };
+    host.trim_end_matches('/').to_string()
+}
+
+/// The key for the openai provider. An `openai_compatible` secret brings its own (possibly no)
+/// key, so an OpenAI key is never sent to another server.
+fn openai_api_key() -> Option<String> {
+    if let Some(compatible) = secrets::find(SecretType::OpenAICompatible) {
+        return compatible.key;
+    }
+    secrets::find(SecretType::OpenAI)
+        .and_then(|s| s.key)
+        .or_else(|| env::var("OPENAI_API_KEY").ok().filter(|v| !v.is_empty()))
+}
+
+fn anthropic_api_key() -> Option<String> {
+    secrets::find(SecretType::Anthropic)
+        .and_then(|s| s.key)
+        .or_else(|| env::var("ANTHROPIC_API_KEY").ok().filter(|v| !v.is_empty()))
+}
+
+/// The error for a provider that can't be used, saying which ones can.
+fn not_configured(provider: &str, requirement: &str) -> String {
+    let configured = backend::configured_backends();
+    if configured.is_empty() {
+        format!(
+            "provider '{}' requires {}. No provider is configured: set OPENAI_API_KEY or ANTHROPIC_API_KEY, \
+             or use provider := 'ollama'",
+            provider, requirement
+        )
+    } else {
+        format!("provider '{}' requires {}. Configured providers: {}", provider, requirement, configured.join(", "))
+    }
+}
+
+/// Runs one request on a fresh tokio runtime; DuckDB calls us from plain threads.
+fn block_on<F: std::future::Future>(future: F) -> Result<F::Output, Box<dyn std::error::Error>> {
+    Ok(tokio::runtime::Runtime::new()?.block_on(future))
+}
+
+pub struct OpenAIFactory;
+
+impl BackendFactory for OpenAIFactory {
+    fn default_model(&self) -> &str {
+        DEFAULT_OPENAI_MODEL
+    }
+
+    fn is_configured(&self) -> bool {
+        openai_api_key().is_some() || openai_base_url().is_some()
+    }
+
+    fn create(&self, model: &str) -> Result<Box<dyn LlmBackend>, String> {
+        let base_url = openai_base_url();
+        let api_key = match (openai_api_key(), &base_url) {
+            (Some(key), _) => key,
+            // Local servers usually don't check the key, but the client has to send one
+            (None, Some(_)) => "none".to_string(),
+            (None, None) => return Err(not_configured("openai", "OPENAI_API_KEY")),
+        };
+        Ok(Box::new(OpenAIBackend {
+            api_key,
+            base_url,
+            model: model.to_string(),
+        }))
+    }
+}
+
+/// OpenAI, or any OpenAI-compatible server when `base_url` is set.
+pub struct OpenAIBackend {
+    api_key: String,
+    base_url: Option<String>,
+    model: String,
+}
+
+impl LlmBackend for OpenAIBackend {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            // OpenAI-compatible servers don't reliably support JSON schema response formats
+            structured_output: self.base_url.is_none(),
+            local: false,
+        }
+    }
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        if request.debug {
+            match &self.base_url {
+                Some(url) => eprintln!("Using OpenAI-compatible API at {}", url),
+                None => eprintln!("Using OpenAI API"),
+            }
+        }
+        block_on(self.call(request))?
+    }
+}
+
+impl OpenAIBackend {
+    async fn call(&self, request: &GenerateRequest<'_>) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let mut config = async_openai::config::OpenAIConfig::default().with_api_key(&self.api_key);
+        if let Some(base_url) = &self.base_url {
+            config = config.with_api_base(base_url.trim_end_matches('/'));
+        }
+        let client = OpenAIClient::with_config(config);
+
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(&self.model);
+        // Structured output binds the answer to the response shape
+        if self.capabilities().structured_output {
+            args.response_format(ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    name: "data_fetch_code".to_string(),
+                    description: Some("Generated JavaScript code and the schema of the rows it returns".to_string()),
+                    schema: Some(response_json_schema()),
+                    strict: Some(true),
+                },
+            });
+        }
+        if let Some(temperature) = request.temperature {
+            args.temperature(temperature);
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            args.max_tokens(max_tokens);
+        }
+        let chat_request = args
+            .messages([
+                ChatCompletionRequestMessage::System(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(SYSTEM_PROMPT)
+                        .build()?
+                ),
+                ChatCompletionRequestMessage::User(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(request.prompt)
+                        .build()?
+                ),
+            ])
+            .build()?;
+
+        let response = client.chat().create(chat_request).await?;
+        let content = response.choices[0].message.content.as_ref()
+            .ok_or("No content in response")?;
+
+        if request.debug {
+            eprintln!("Raw OpenAI response: {}", content);
+        }
+
+        parse_response(content, request.debug)
+    }
+}
+
+pub struct AnthropicFactory;
+
+impl BackendFactory for AnthropicFactory {
+    fn default_model(&self) -> &str {
+        DEFAULT_ANTHROPIC_MODEL
+    }
+
+    fn is_configured(&self) -> bool {
+        anthropic_api_key().is_some()
+    }
+
+    fn create(&self, model: &str) -> Result<Box<dyn LlmBackend>, String> {
+        Ok(Box::new(AnthropicBackend {
+            api_key: anthropic_api_key().ok_or_else(|| not_configured("anthropic", "ANTHROPIC_API_KEY"))?,
+            model: model.to_string(),
+        }))
+    }
+}
+
+pub struct AnthropicBackend {
+    api_key: String,
+    model: String,
+}
+
+impl LlmBackend for AnthropicBackend {
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            structured_output: true,
+            local: false,
+        }
+    }
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        if request.debug {
+            eprintln!("Using Anthropic API");
+        }
+        block_on(self.call(request))?
+    }
+}
+
+impl AnthropicBackend {
+    async fn call(&self, request: &GenerateRequest<'_>) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        // Forcing a tool call makes Claude answer with arguments matching the response schema
+        let mut body = serde_json::json!({
+            "model": self.model,
+            "max_tokens": request.max_tokens.unwrap_or(4096),
+            "messages": [{ "role": "user", "content": request.prompt }],
+            "tools": [{
+                "name": RESPONSE_TOOL_NAME,
+                "description": "Return the generated JavaScript code and the schema of the rows it returns",
+                "input_schema": response_json_schema(),
+            }],
+            "tool_choice": { "type": "tool", "name": RESPONSE_TOOL_NAME },
+        });
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = temperature.into();
+        }
+
+        let response = reqwest::Client::new()
+            .post("https://api.anthropic.com/v1/messages")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .json(&body)
+            .send()
+            .await?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await?;
+        if !status.is_success() {
+            let message = body["error"]["message"].as_str().unwrap_or("no error message");
+            return Err(format!("Anthropic returned {}: {}", status, message).into());
+        }
+
+        let content = body["content"].as_array().ok_or("No content in Anthropic response")?;
+        if let Some(tool_use) = content.iter().find(|block| block["type"] == "tool_use") {
+            if request.debug {
+                eprintln!("Raw Anthropic tool input: {}", tool_use["input"]);
+            }
+            return Ok(serde_json::from_value(tool_use["input"].clone())?);
+        }
+
+        // Some models answer in text despite tool_choice
+        let text = content
+            .iter()
+            .find_map(|block| block["text"].as_str())
+            .ok_or("No tool call or text content in Anthropic response")?;
+        if request.debug {
+            eprintln!("Raw Anthropic response: {}", text);
+        }
+        parse_response(text, request.debug)
+    }
+}
+
+pub struct OllamaFactory;
+
+impl BackendFactory for OllamaFactory {
+    fn default_model(&self) -> &str {
+        DEFAULT_OLLAMA_MODEL
+    }
+
+    /// Ollama needs no key, so it only counts as configured once a host has been set.
+    fn is_configured(&self) -> bool {
+        settings::get("wizard_ollama_host").is_some() || env::var("OLLAMA_HOST").is_ok_and(|v| !v.is_empty())
+    }
+
+    fn create(&self, model: &str) -> Result<Box<dyn LlmBackend>, String> {
+        Ok(Box::new(OllamaBackend {
+            host: ollama_host(),
+            model: model.to_string(),
+        }))
+    }
+}
+
+/// A local Ollama daemon, talking to its native chat API.
+pub struct OllamaBackend {
+    host: String,
+    model: String,
+}
+
+impl LlmBackend for OllamaBackend {
+    fn name(&self) -> &str {
+        "ollama"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            structured_output: false,
+            local: true,
+        }
+    }
+
+    fn generate(&self, request: &GenerateRequest) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        if request.debug {
+            eprintln!("Using Ollama at {}", self.host);
+        }
+        block_on(self.call(request))?
+    }
+}
+
+impl OllamaBackend {
+    async fn call(&self, request: &GenerateRequest<'_>) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+        let host = &self.host;
+        let not_running = |e: reqwest::Error| {
+            format!("Could not reach Ollama at {} ({}). Is the daemon running? Start it with `ollama serve`", host, e)
+        };
+
+        // Check the model has been pulled, so a missing one gets a clear error instead of a 404
+        let tags: serde_json::Value = client
+            .get(format!("{}/api/tags", host))
+            .send()
+            .await
+            .map_err(not_running)?
+            .error_for_status()?
+            .json()
+            .await?;
+        let pulled = tags["models"].as_array().is_some_and(|models| {
+            models.iter().filter_map(|m| m["name"].as_str()).any(|name| {
+                name == self.model || name.strip_suffix(":latest") == Some(self.model.as_str())
+            })
+        });
+        if !pulled {
+            return Err(format!(
+                "Ollama model '{}' has not been pulled. Run `ollama pull {}` first",
+                self.model, self.model
+            ).into());
+        }
+
+        let mut options = serde_json::Map::new();
+        if let Some(temperature) = request.temperature {
+            options.insert("temperature".to_string(), temperature.into());
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            options.insert("num_predict".to_string(), max_tokens.into());
+        }
+        let body = serde_json::json!({
+            "model": self.model,
+            "messages": [
+                { "role": "system", "content": SYSTEM_PROMPT },
+                { "role": "user", "content": request.prompt }
+            ],
+            "format": "json",
+            "stream": false,
+            "options": options,
+        });
+
+        let response = client
+            .post(format!("{}/api/chat", host))
+            .json(&body)
+            .send()
+            .await
+            .map_err(not_running)?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await?;
+        if !status.is_success() {
+            let message = body["error"].as_str().unwrap_or("no error message");
+            return Err(format!("Ollama returned {}: {}", status, message).into());
+        }
+
+        let content = body["message"]["content"].as_str().ok_or("No content in Ollama response")?;
+        if request.debug {
+            eprintln!("Raw Ollama response: {}", content);
+        }
+
+        parse_response(content, request.debug)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers every request with `answer(path)`, a status and a JSON body, from a thread
+    /// listening on a free port. Returns the server's URL.
+    fn serve(answer: fn(&str) -> (u16, &'static str)) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    match header.trim().split_once(':') {
+                        Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                reader.read_exact(&mut vec![0; content_length]).unwrap();
+
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = answer(path);
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    fn generate(backend: &dyn LlmBackend) -> Result<LLMResponse, Box<dyn std::error::Error>> {
+        backend.generate(&GenerateRequest {
+            prompt: "prompt",
+            query: "query",
+            revision: false,
+            temperature: None,
+            max_tokens: None,
+            debug: false,
+        })
+    }
+
+    fn ollama(host: String) -> OllamaBackend {
+        OllamaBackend {
+            host,
+            model: DEFAULT_OLLAMA_MODEL.to_string(),
+        }
+    }
+
+    #[test]
+    fn openai_compatible_server_answers_without_structured_output() {
+        // Without structured output, models tend to wrap the JSON in prose and a code fence
+        let host = serve(|path| match path {
+            "/v1/chat/completions" => (
+                200,
+                r#"{"id": "chatcmpl-stub", "object": "chat.completion", "created": 0, "model": "stub", "choices": [{"index": 0, "message": {"role": "assistant", "content": "Here is the program:\n```json\n{\"javascript_code\": \"async function fetch_data() { return [{ id: 1, message: 'stub server response', value: 42.0 }]; }\", \"schema\": [{\"name\": \"id\", \"data_type\": \"bigint\"}, {\"name\": \"message\", \"data_type\": \"varchar\"}, {\"name\": \"value\", \"data_type\": \"double\"}]}\n```"}, "finish_reason": "stop"}], "usage": {"prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0}}"#,
+            ),
+            _ => (404, "{}"),
+        });
+        let backend = OpenAIBackend {
+            api_key: "none".to_string(),
+            base_url: Some(format!("{}/v1", host)),
+            model: "stub".to_string(),
+        };
+        let response = generate(&backend).unwrap();
+        assert!(response.javascript_code.contains("stub server response"));
+        let columns: Vec<&str> = response.schema.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(columns, ["id", "message", "value"]);
+    }
+
+    #[test]
+    fn ollama_answers_once_the_model_is_pulled() {
+        let host = serve(|path| match path {
+            "/api/tags" => (200, r#"{"models": [{"name": "mistral:latest"}, {"name": "llama3.1:latest"}]}"#),
+            "/api/chat" => (
+                200,
+                r#"{"message": {"role": "assistant", "content": "{\"javascript_code\": \"async function fetch_data() { return []; }\", \"schema\": []}"}}"#,
+            ),
+            _ => (404, "{}"),
+        });
+        let response = generate(&ollama(host)).unwrap();
+        assert!(response.javascript_code.contains("fetch_data"));
+    }
+
+    #[test]
+    fn ollama_reports_a_model_that_has_not_been_pulled() {
+        let host = serve(|path| match path {
+            "/api/tags" => (200, r#"{"models": [{"name": "mistral:latest"}]}"#),
+            _ => (404, "{}"),
+        });
+        let error = generate(&ollama(host)).unwrap_err().to_string();
+        assert_eq!(error, "Ollama model 'llama3.1' has not been pulled. Run `ollama pull llama3.1` first");
+    }
+
+    #[test]
+    fn ollama_reports_a_daemon_that_is_not_running() {
+        // Nothing listens on a port that was free a moment ago
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let host = format!("http://127.0.0.1:{}", port);
+        let error = generate(&ollama(host.clone())).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("Could not reach Ollama at {}", host)), "{}", error);
+        assert!(error.ends_with("Is the daemon running? Start it with `ollama serve`"), "{}", error);
+    }
+}
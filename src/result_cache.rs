@@ -0,0 +1,93 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::js_executor::{JsExecutor, JsRow};
+use crate::options::parse_duration;
+use crate::settings;
+
+type Rows = Vec<JsRow>;
+
+// Rows fetched by JavaScript, keyed by a hash of the code that produced them and of what it ran
+// with (timeout, secrets, cassette). Kept in memory
+// only: this is meant to absorb bursts of identical queries, not to replace the remote API.
+lazy_static::lazy_static! {
+    static ref RESULT_CACHE: Mutex<HashMap<u64, CachedRows>> = Mutex::new(HashMap::new());
+}
+
+struct CachedRows {
+    rows: Rows,
+    fetched_at: Instant,
+}
+
+fn result_key(executor: &JsExecutor, code: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code.hash(&mut hasher);
+    executor.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The result TTL from the `wizard_result_ttl` setting; `None` (the default) disables result caching.
+pub fn ttl_from_settings() -> Result<Option<Duration>, String> {
+    match settings::get("wizard_result_ttl") {
+        Some(value) => parse_duration(&value)
+            .map(|ttl| Some(ttl).filter(|ttl| !ttl.is_zero()))
+            .map_err(|e| format!("wizard_result_ttl: {}", e)),
+        None => Ok(None),
+    }
+}
+
+/// Runs `code`, reusing rows from an earlier run of the same code if they are younger than `ttl`.
+/// With no `ttl` the code always runs and nothing is cached; with `refresh` it always runs and
+/// its rows replace the cached ones. Rows `cacheable` rejects, such as error rows, aren't kept.
+pub fn execute_cached(
+    executor: &JsExecutor,
+    code: &str,
+    ttl: Option<Duration>,
+    refresh: bool,
+    cacheable: impl Fn(&[JsRow]) -> bool,
+    debug: bool,
+) -> Result<Rows, Box<dyn std::error::Error>> {
+    let Some(ttl) = ttl else {
+        return executor.execute_code(code, debug);
+    };
+
+    let key = result_key(executor, code);
+    if let Some(cached) = RESULT_CACHE.lock().unwrap().get(&key).filter(|_| !refresh) {
+        if cached.fetched_at.elapsed() <= ttl {
+            if debug {
+                eprintln!("Using cached rows fetched {:?} ago", cached.fetched_at.elapsed());
+            }
+            return Ok(cached.rows.clone());
+        }
+    }
+
+    let rows = executor.execute_code(code, debug)?;
+    if !cacheable(&rows) {
+        return Ok(rows);
+    }
+
+    let mut cache = RESULT_CACHE.lock().unwrap();
+    // Drop anything this call would consider stale so the cache doesn't grow without bound
+    cache.retain(|_, cached| cached.fetched_at.elapsed() <= ttl);
+    cache.insert(
+        key,
+        CachedRows {
+            rows: rows.clone(),
+            fetched_at: Instant::now(),
+        },
+    );
+
+    Ok(rows)
+}
+
+/// Drops all cached rows. Returns how many result sets were removed.
+pub fn clear() -> usize {
+    let mut cache = RESULT_CACHE.lock().unwrap();
+    let count = cache.len();
+    cache.clear();
+    count
+}
@@ -0,0 +1,461 @@
+use duckdb::{
+    core::{DataChunkHandle, Inserter, LogicalTypeHandle, LogicalTypeId},
+    vtab::{BindInfo, InitInfo, TableFunctionInfo, VTab},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fmt, fs,
+    io::Write,
+    path::PathBuf,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex, MutexGuard},
+};
+
+use crate::options::{named_bool, named_varchar};
+use crate::settings;
+
+const SECRETS_FILE_NAME: &str = "secrets.json";
+
+// API keys created with `wizard_create_secret`. DuckDB's C extension API can't register secret
+// types for `CREATE SECRET`, so we keep our own store. Persistent secrets are loaded on first use.
+lazy_static::lazy_static! {
+    static ref SECRETS: Mutex<SecretStore> = Mutex::new(SecretStore { secrets: Vec::new(), dir: None, loaded: false });
+}
+
+struct SecretStore {
+    secrets: Vec<Secret>,
+    /// The directory the persistent secrets were read from.
+    dir: Option<PathBuf>,
+    loaded: bool,
+}
+
+/// The store, with the persistent secrets of the current `wizard_secret_dir`. When the
+/// directory changes, those of the old one are dropped and the new one's are read.
+fn store() -> MutexGuard<'static, SecretStore> {
+    let mut store = SECRETS.lock().unwrap();
+    let dir = secret_dir();
+    if !store.loaded || dir != store.dir {
+        store.secrets.retain(|s| !s.persistent);
+        // Secrets created this session win over persisted ones of the same name
+        let persisted: Vec<Secret> = read_persisted(dir.as_ref())
+            .into_iter()
+            .filter(|p| !store.secrets.iter().any(|s| s.name == p.name))
+            .collect();
+        store.secrets.extend(persisted);
+        store.dir = dir;
+        store.loaded = true;
+    }
+    store
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretType {
+    /// Key for the OpenAI API
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Key for the Anthropic API
+    Anthropic,
+    /// Base URL (and optional key) of an OpenAI-compatible server
+    #[serde(rename = "openai_compatible")]
+    OpenAICompatible,
+    /// Key for an API the generated JavaScript calls, only sent to URLs under its scope
+    Http,
+}
+
+impl SecretType {
+    /// Also accepts `wizard_openai` and `wizard_anthropic`, the names these would have as
+    /// `CREATE SECRET` types.
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "openai" | "wizard_openai" => Ok(SecretType::OpenAI),
+            "anthropic" | "wizard_anthropic" => Ok(SecretType::Anthropic),
+            "openai_compatible" => Ok(SecretType::OpenAICompatible),
+            "http" => Ok(SecretType::Http),
+            _ => Err(format!(
+                "Unknown secret type '{}'. Supported types: openai, anthropic, openai_compatible, http",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SecretType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SecretType::OpenAI => "openai",
+            SecretType::Anthropic => "anthropic",
+            SecretType::OpenAICompatible => "openai_compatible",
+            SecretType::Http => "http",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Secret {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub secret_type: SecretType,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// URL prefix the key may be sent to (`http` secrets)
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+impl Secret {
+    fn validate(&self) -> Result<(), String> {
+        let missing = |what: &str| Err(format!("{} secrets require {}", self.secret_type, what));
+        match self.secret_type {
+            SecretType::OpenAI | SecretType::Anthropic if self.key.is_none() => missing("a key"),
+            SecretType::OpenAICompatible if self.base_url.is_none() => missing("a base_url"),
+            SecretType::Http if self.key.is_none() => missing("a key"),
+            SecretType::Http if self.scope.is_none() => missing("a scope, e.g. 'https://api.github.com'"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The `wizard_secret_dir` setting (or `WIZARD_SECRET_DIR`, where an empty value disables
+/// persistent secrets), else `~/.duckdb/wizard`.
+fn secret_dir() -> Option<PathBuf> {
+    if let Some(dir) = settings::get("wizard_secret_dir") {
+        return Some(PathBuf::from(dir));
+    }
+    if env::var("WIZARD_SECRET_DIR").is_ok_and(|dir| dir.is_empty()) {
+        return None;
+    }
+    env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .ok()
+        .map(|home| PathBuf::from(home).join(".duckdb").join("wizard"))
+}
+
+fn read_persisted(dir: Option<&PathBuf>) -> Vec<Secret> {
+    dir.and_then(|dir| fs::read_to_string(dir.join(SECRETS_FILE_NAME)).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the persistent secrets to disk, readable only by the current user.
+fn persist(secrets: &[Secret]) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(dir) = secret_dir() else {
+        return Err("Persistent secrets are disabled because WIZARD_SECRET_DIR is empty".into());
+    };
+    fs::create_dir_all(&dir)?;
+
+    let persistent: Vec<&Secret> = secrets.iter().filter(|s| s.persistent).collect();
+    let path = dir.join(SECRETS_FILE_NAME);
+    let tmp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+
+    let mut file_options = fs::OpenOptions::new();
+    file_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        file_options.mode(0o600);
+    }
+    file_options
+        .open(&tmp_path)?
+        .write_all(serde_json::to_string_pretty(&persistent)?.as_bytes())?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+pub fn create(secret: Secret, replace: bool) -> Result<(), Box<dyn std::error::Error>> {
+    secret.validate()?;
+    let mut store = store();
+    let secrets = &mut store.secrets;
+    let existing = secrets.iter().position(|s| s.name == secret.name);
+    if existing.is_some() && !replace {
+        return Err(format!("Secret '{}' already exists. Pass replace := true to overwrite it", secret.name).into());
+    }
+
+    let was_persistent = existing.is_some_and(|idx| secrets[idx].persistent);
+    let persistent = secret.persistent;
+    if let Some(idx) = existing {
+        secrets.remove(idx);
+    }
+    secrets.push(secret);
+    if persistent || was_persistent {
+        persist(secrets)?;
+    }
+    Ok(())
+}
+
+/// Removes the secret called `name`, including its persisted copy. Returns whether it existed.
+pub fn remove(name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut store = store();
+    let secrets = &mut store.secrets;
+    let Some(idx) = secrets.iter().position(|s| s.name == name) else {
+        return Ok(false);
+    };
+    let removed = secrets.remove(idx);
+    if removed.persistent {
+        persist(secrets)?;
+    }
+    Ok(true)
+}
+
+pub fn list() -> Vec<Secret> {
+    store().secrets.clone()
+}
+
+/// The most recently created secret of `secret_type`.
+pub fn find(secret_type: SecretType) -> Option<Secret> {
+    store().secrets.iter().rev().find(|s| s.secret_type == secret_type).cloned()
+}
+
+/// The `http` secrets, which generated JavaScript can use.
+pub fn http_secrets() -> Vec<Secret> {
+    store()
+        .secrets
+        .iter()
+        .filter(|s| s.secret_type == SecretType::Http)
+        .cloned()
+        .collect()
+}
+
+/// What generated code sees in place of the key of the `http` secret `name`. `fetch()` swaps it
+/// for the key, so the key itself never reaches JavaScript.
+pub fn placeholder(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("__WIZARD_SECRET_{}__", name)
+}
+
+/// JavaScript that defines `WIZARD_SECRETS` (`{name: {key, scope}}`, with placeholder keys) and
+/// wraps `fetch()` to put the real keys into requests, refusing URLs outside a key's scope. It
+/// runs before any cassette prelude, so recorded requests hold placeholders rather than keys.
+pub fn fetch_prelude(secrets: &[Secret]) -> String {
+    let visible: serde_json::Map<String, serde_json::Value> = secrets
+        .iter()
+        .map(|s| (s.name.clone(), serde_json::json!({ "key": placeholder(&s.name), "scope": s.scope })))
+        .collect();
+    let hidden: Vec<serde_json::Value> = secrets
+        .iter()
+        .map(|s| serde_json::json!({ "name": s.name, "token": placeholder(&s.name), "key": s.key, "scope": s.scope }))
+        .collect();
+    SECRETS_PRELUDE
+        .replace("__VISIBLE__", &serde_json::Value::Object(visible).to_string())
+        .replace("__SECRETS__", &serde_json::Value::Array(hidden).to_string())
+}
+
+/// Placeholders are replaced in the URL, the headers and a string body. A URL is under a scope
+/// if it is the scope or continues it with a path, query or fragment.
+const SECRETS_PRELUDE: &str = r#"globalThis.WIZARD_SECRETS = Object.freeze(__VISIBLE__);
+globalThis.fetch = ((realFetch, secrets) => {
+    const inScope = (url, scope) =>
+        url.startsWith(scope) && (scope.endsWith("/") || url.length === scope.length || "/?#".includes(url[scope.length]));
+    return async (input, init = {}) => {
+        const request = new Request(input, init);
+        let url = request.url;
+        const headers = [...request.headers];
+        let body = typeof init.body === "string" ? init.body : undefined;
+        const used = secrets.filter(({ token }) =>
+            url.includes(token) || body?.includes(token) || headers.some(([, value]) => value.includes(token))
+        );
+        if (used.length === 0) {
+            return realFetch(input, init);
+        }
+        for (const { name, token, key, scope } of used) {
+            if (!inScope(request.url, scope)) {
+                throw new Error(`WIZARD_SECRETS.${name} may only be sent to URLs under ${scope}, not ${request.url}`);
+            }
+            url = url.replaceAll(token, encodeURIComponent(key));
+            body = body?.replaceAll(token, key);
+            for (const header of headers) {
+                header[1] = header[1].replaceAll(token, key);
+            }
+        }
+        return realFetch(url, { ...init, method: request.method, headers, body: body ?? init.body });
+    };
+})(globalThis.fetch, __SECRETS__);
+"#;
+
+#[repr(C)]
+pub struct WizardCreateSecretBindData {
+    secret: Secret,
+    replace: bool,
+}
+
+#[repr(C)]
+pub struct WizardSecretInitData {
+    done: AtomicBool,
+}
+
+/// `CALL wizard_create_secret('github', type := 'http', key := '...', scope := 'https://api.github.com')`
+/// - stores an API key. `persistent := true` keeps it across sessions.
+pub struct WizardCreateSecretVTab;
+
+impl VTab for WizardCreateSecretVTab {
+    type InitData = WizardSecretInitData;
+    type BindData = WizardCreateSecretBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let name = bind.get_parameter(0).to_string();
+        let secret_type = named_varchar(bind, "type").ok_or("wizard_create_secret requires a type")?;
+        let secret = Secret {
+            name,
+            secret_type: SecretType::parse(&secret_type)?,
+            key: named_varchar(bind, "key"),
+            base_url: named_varchar(bind, "base_url"),
+            scope: named_varchar(bind, "scope"),
+            persistent: named_bool(bind, "persistent").unwrap_or(false),
+        };
+        // Only checked here; the secret is stored when the statement runs.
+        secret.validate()?;
+
+        bind.add_result_column("name", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("type", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("persistent", LogicalTypeHandle::from(LogicalTypeId::Boolean));
+
+        Ok(WizardCreateSecretBindData {
+            secret,
+            replace: named_bool(bind, "replace").unwrap_or(false),
+        })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardSecretInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        let init_data = func.get_init_data();
+        let bind_data = func.get_bind_data();
+        let secret = &bind_data.secret;
+
+        if init_data.done.swap(true, Ordering::Relaxed) {
+            output.set_len(0);
+            return Ok(());
+        }
+        create(secret.clone(), bind_data.replace)?;
+
+        output.flat_vector(0).insert(0, secret.name.as_str());
+        output.flat_vector(1).insert(0, secret.secret_type.to_string().as_str());
+        output.flat_vector(2).as_mut_slice::<bool>()[0] = secret.persistent;
+        output.set_len(1);
+
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
+    }
+
+    fn named_parameters() -> Option<Vec<(String, LogicalTypeHandle)>> {
+        Some(vec![
+            ("type".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("key".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("base_url".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("scope".to_string(), LogicalTypeHandle::from(LogicalTypeId::Varchar)),
+            ("persistent".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean)),
+            ("replace".to_string(), LogicalTypeHandle::from(LogicalTypeId::Boolean)),
+        ])
+    }
+}
+
+#[repr(C)]
+pub struct WizardDropSecretBindData {
+    name: String,
+}
+
+/// `CALL wizard_drop_secret('github')` - removes a secret, including its persisted copy.
+pub struct WizardDropSecretVTab;
+
+impl VTab for WizardDropSecretVTab {
+    type InitData = WizardSecretInitData;
+    type BindData = WizardDropSecretBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let name = bind.get_parameter(0).to_string();
+        bind.add_result_column("dropped", LogicalTypeHandle::from(LogicalTypeId::Boolean));
+        Ok(WizardDropSecretBindData { name })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardSecretInitData { done: AtomicBool::new(false) })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        if func.get_init_data().done.swap(true, Ordering::Relaxed) {
+            output.set_len(0);
+            return Ok(());
+        }
+        output.flat_vector(0).as_mut_slice::<bool>()[0] = remove(&func.get_bind_data().name)?;
+        output.set_len(1);
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![LogicalTypeHandle::from(LogicalTypeId::Varchar)])
+    }
+}
+
+#[repr(C)]
+pub struct WizardSecretsBindData {
+    secrets: Vec<Secret>,
+}
+
+#[repr(C)]
+pub struct WizardSecretsInitData {
+    current_row: AtomicUsize,
+}
+
+/// `SELECT * FROM wizard_secrets()` - lists the secrets without their keys.
+pub struct WizardSecretsVTab;
+
+impl VTab for WizardSecretsVTab {
+    type InitData = WizardSecretsInitData;
+    type BindData = WizardSecretsBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        bind.add_result_column("name", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("type", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("scope", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("base_url", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("persistent", LogicalTypeHandle::from(LogicalTypeId::Boolean));
+
+        Ok(WizardSecretsBindData { secrets: list() })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardSecretsInitData {
+            current_row: AtomicUsize::new(0),
+        })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        let init_data = func.get_init_data();
+        let bind_data = func.get_bind_data();
+
+        let current_row = init_data.current_row.load(Ordering::Relaxed);
+        let chunk_size = std::cmp::min(2048, bind_data.secrets.len().saturating_sub(current_row));
+        let end_row = current_row + chunk_size;
+
+        for (chunk_idx, secret) in bind_data.secrets[current_row..end_row].iter().enumerate() {
+            output.flat_vector(0).insert(chunk_idx, secret.name.as_str());
+            output.flat_vector(1).insert(chunk_idx, secret.secret_type.to_string().as_str());
+            for (col_idx, value) in [(2, &secret.scope), (3, &secret.base_url)] {
+                let mut vector = output.flat_vector(col_idx);
+                match value {
+                    Some(value) => vector.insert(chunk_idx, value.as_str()),
+                    None => vector.set_null(chunk_idx),
+                }
+            }
+            output.flat_vector(4).as_mut_slice::<bool>()[chunk_idx] = secret.persistent;
+        }
+
+        output.set_len(chunk_size);
+        init_data.current_row.store(end_row, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![])
+    }
+}
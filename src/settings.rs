@@ -0,0 +1,138 @@
+use duckdb::{
+    core::{DataChunkHandle, Inserter, LogicalTypeHandle, LogicalTypeId},
+    vtab::{BindInfo, InitInfo, TableFunctionInfo, VTab},
+    Result,
+};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
+
+use crate::backend;
+
+// Extension settings changed with `CALL wizard_set(name, value)`. The DuckDB C extension
+// API doesn't let us register options for `SET`, so we keep our own.
+lazy_static::lazy_static! {
+    static ref SETTINGS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Known settings and their descriptions. Each can also be provided through the upper-cased
+/// environment variable of the same name (e.g. `WIZARD_CACHE_TTL`).
+pub const KNOWN_SETTINGS: &[(&str, &str)] = &[
+    ("wizard_provider", "LLM provider used when the query doesn't name one: openai, anthropic, ollama or mock"),
+    ("wizard_model", "Model used when the query doesn't name one, for any provider"),
+    ("wizard_openai_model", "Model used for OpenAI when the query doesn't name one (default: gpt-4o)"),
+    ("wizard_anthropic_model", "Model used for Anthropic when the query doesn't name one (default: claude-3-haiku-20240307)"),
+    ("wizard_ollama_model", "Model used for Ollama when the query doesn't name one (default: llama3.1)"),
+    ("wizard_ollama_host", "Ollama daemon to use, e.g. 'http://localhost:11434' (default: OLLAMA_HOST)"),
+    ("wizard_openai_base_url", "OpenAI-compatible server to use instead of api.openai.com, e.g. 'http://localhost:8000/v1'"),
+    ("wizard_mock_fixtures", "Directory of fixture files answering prompts for provider 'mock'"),
+    ("wizard_cassette", "File LLM answers and fetch() calls are recorded to or replayed from"),
+    ("wizard_cassette_mode", "'record' to write LLM answers and fetch() calls to wizard_cassette, 'replay' to answer from it"),
+    ("wizard_temperature", "Sampling temperature between 0 and 2, or 0 and 1 for Anthropic (default: the provider's)"),
+    ("wizard_api_catalog", "JSON or YAML file of APIs generated code should use, alongside those from wizard_register_api"),
+    ("wizard_prompt_template", "Prompt template replacing the built-in one, with {{date}}, {{query}}, {{columns}}, {{secrets}}, {{catalog}}, {{rules}} and {{format}} placeholders"),
+    ("wizard_prompt_template_file", "File holding the prompt template, used when wizard_prompt_template isn't set"),
+    ("wizard_prompt_rules", "Extra rules added to the prompt, e.g. which internal APIs to use"),
+    ("wizard_max_tokens", "Maximum length of the LLM's response in tokens (default: the provider's, 4096 for Anthropic)"),
+    ("wizard_secret_dir", "Directory of persistent secrets (default: ~/.duckdb/wizard)"),
+    ("wizard_cache_dir", "Directory of the persistent code cache (default: ~/.cache/duckdb_wizard)"),
+    ("wizard_cache_ttl", "How long cached code stays valid, e.g. '12h' or 'never' (default: never)"),
+    ("wizard_result_ttl", "How long fetched rows are reused, e.g. '60s' (default: rows are not cached)"),
+    ("wizard_repair_attempts", "How many times to ask the LLM to fix rows that don't match the schema (default: 2)"),
+    ("wizard_heal_attempts", "How many times to ask the LLM to fix code that throws (default: 0, no retries)"),
+];
+
+/// Returns the value of a setting: the value from `wizard_set` if any, otherwise the
+/// environment variable of the same name.
+pub fn get(name: &str) -> Option<String> {
+    if let Some(value) = SETTINGS.lock().unwrap().get(name) {
+        return Some(value.clone()).filter(|v| !v.is_empty());
+    }
+    env::var(name.to_uppercase()).ok().filter(|v| !v.is_empty())
+}
+
+pub fn set(name: &str, value: &str) -> Result<(), String> {
+    let name = name.to_lowercase();
+    check_name(&name)?;
+    SETTINGS.lock().unwrap().insert(name, value.to_string());
+    Ok(())
+}
+
+/// Fails unless `name` (lower-cased) is a setting `set` accepts.
+fn check_name(name: &str) -> Result<(), String> {
+    if !KNOWN_SETTINGS.iter().any(|(known, _)| *known == name) && !is_backend_model(&name) {
+        let known: Vec<&str> = KNOWN_SETTINGS.iter().map(|(n, _)| *n).collect();
+        return Err(format!("Unknown setting '{}'. Available settings: {}", name, known.join(", ")));
+    }
+    Ok(())
+}
+
+/// `wizard_<backend>_model` for backends registered by embedders.
+fn is_backend_model(name: &str) -> bool {
+    name.strip_prefix("wizard_")
+        .and_then(|rest| rest.strip_suffix("_model"))
+        .is_some_and(|backend| backend::factory(backend).is_some())
+}
+
+#[repr(C)]
+pub struct WizardSetBindData {
+    name: String,
+    value: String,
+}
+
+#[repr(C)]
+pub struct WizardSetInitData {
+    done: AtomicBool,
+}
+
+/// `CALL wizard_set('wizard_cache_ttl', '1h')` - changes a setting for this process.
+pub struct WizardSetVTab;
+
+impl VTab for WizardSetVTab {
+    type InitData = WizardSetInitData;
+    type BindData = WizardSetBindData;
+
+    fn bind(bind: &BindInfo) -> Result<Self::BindData, Box<dyn std::error::Error>> {
+        let name = bind.get_parameter(0).to_string().to_lowercase();
+        let value = bind.get_parameter(1).to_string();
+        // Only checked here; the setting changes when the statement runs.
+        check_name(&name)?;
+
+        bind.add_result_column("name", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+        bind.add_result_column("value", LogicalTypeHandle::from(LogicalTypeId::Varchar));
+
+        Ok(WizardSetBindData { name, value })
+    }
+
+    fn init(_: &InitInfo) -> Result<Self::InitData, Box<dyn std::error::Error>> {
+        Ok(WizardSetInitData {
+            done: AtomicBool::new(false),
+        })
+    }
+
+    fn func(func: &TableFunctionInfo<Self>, output: &mut DataChunkHandle) -> Result<(), Box<dyn std::error::Error>> {
+        let init_data = func.get_init_data();
+        let bind_data = func.get_bind_data();
+
+        if init_data.done.swap(true, Ordering::Relaxed) {
+            output.set_len(0);
+            return Ok(());
+        }
+
+        set(&bind_data.name, &bind_data.value)?;
+        output.flat_vector(0).insert(0, bind_data.name.as_str());
+        output.flat_vector(1).insert(0, bind_data.value.as_str());
+        output.set_len(1);
+
+        Ok(())
+    }
+
+    fn parameters() -> Option<Vec<LogicalTypeHandle>> {
+        Some(vec![
+            LogicalTypeHandle::from(LogicalTypeId::Varchar),
+            LogicalTypeHandle::from(LogicalTypeId::Varchar),
+        ])
+    }
+}
@@ -0,0 +1,806 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use std::fmt;
+use duckdb::core::{DataChunkHandle, FlatVector, Inserter, ListVector, LogicalTypeHandle, LogicalTypeId, StructVector};
+
+use libduckdb_sys as ffi;
+
+use crate::js_executor::{JsRow, JsValue};
+use crate::llm::ColumnSchema;
+
+/// The DuckDB column types a schema `data_type` can ask for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnType {
+    Varchar,
+    Boolean,
+    Tinyint,
+    Smallint,
+    Integer,
+    Bigint,
+    Float,
+    Double,
+    Decimal(u8, u8),
+    Date,
+    Time,
+    Timestamp,
+    TimestampTz,
+    Json,
+    List(Box<ColumnType>),
+    Struct(Vec<(String, ColumnType)>),
+    Map(Box<ColumnType>, Box<ColumnType>),
+}
+
+impl ColumnType {
+    /// Parses a schema data type such as `bigint`, `decimal(10,2)`, `timestamptz`, `varchar[]`,
+    /// `struct(name varchar, tags varchar[])` or `map(varchar, double)`.
+    /// Unknown types fall back to varchar.
+    pub fn parse(data_type: &str) -> Self {
+        parse_type(data_type, false).unwrap_or(ColumnType::Varchar)
+    }
+
+    /// Like `parse`, but returns `None` for anything it doesn't recognise.
+    pub fn try_parse(data_type: &str) -> Option<Self> {
+        parse_type(data_type, true)
+    }
+
+    /// The column type for a schema entry. Structs may be described either inline
+    /// (`struct(a varchar)`) or with `data_type: "struct"` (or `"struct[]"`) plus `fields`.
+    pub fn from_schema(column: &ColumnSchema) -> Self {
+        match &column.fields {
+            Some(fields) if !fields.is_empty() => {
+                let struct_type = ColumnType::Struct(
+                    fields.iter().map(|f| (f.name.clone(), ColumnType::from_schema(f))).collect(),
+                );
+                if column.data_type.trim().ends_with("[]") {
+                    ColumnType::List(Box::new(struct_type))
+                } else {
+                    struct_type
+                }
+            }
+            _ => ColumnType::parse(&column.data_type),
+        }
+    }
+
+    pub fn logical_type(&self) -> LogicalTypeHandle {
+        match self {
+            ColumnType::Varchar => LogicalTypeHandle::from(LogicalTypeId::Varchar),
+            ColumnType::Boolean => LogicalTypeHandle::from(LogicalTypeId::Boolean),
+            ColumnType::Tinyint => LogicalTypeHandle::from(LogicalTypeId::Tinyint),
+            ColumnType::Smallint => LogicalTypeHandle::from(LogicalTypeId::Smallint),
+            ColumnType::Integer => LogicalTypeHandle::from(LogicalTypeId::Integer),
+            ColumnType::Bigint => LogicalTypeHandle::from(LogicalTypeId::Bigint),
+            ColumnType::Float => LogicalTypeHandle::from(LogicalTypeId::Float),
+            ColumnType::Double => LogicalTypeHandle::from(LogicalTypeId::Double),
+            ColumnType::Decimal(width, scale) => LogicalTypeHandle::decimal(*width, *scale),
+            ColumnType::Date => LogicalTypeHandle::from(LogicalTypeId::Date),
+            ColumnType::Time => LogicalTypeHandle::from(LogicalTypeId::Time),
+            ColumnType::Timestamp => LogicalTypeHandle::from(LogicalTypeId::Timestamp),
+            ColumnType::TimestampTz => LogicalTypeHandle::from(LogicalTypeId::TimestampTZ),
+            ColumnType::Json => json_logical_type(),
+            ColumnType::List(element) => LogicalTypeHandle::list(&element.logical_type()),
+            ColumnType::Struct(fields) => {
+                let children: Vec<(&str, LogicalTypeHandle)> =
+                    fields.iter().map(|(name, t)| (name.as_str(), t.logical_type())).collect();
+                LogicalTypeHandle::struct_type(&children)
+            }
+            ColumnType::Map(key, value) => LogicalTypeHandle::map(&key.logical_type(), &value.logical_type()),
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    /// Formats the type in the schema vocabulary `parse` understands.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnType::Varchar => write!(f, "varchar"),
+            ColumnType::Boolean => write!(f, "boolean"),
+            ColumnType::Tinyint => write!(f, "tinyint"),
+            ColumnType::Smallint => write!(f, "smallint"),
+            ColumnType::Integer => write!(f, "integer"),
+            ColumnType::Bigint => write!(f, "bigint"),
+            ColumnType::Float => write!(f, "float"),
+            ColumnType::Double => write!(f, "double"),
+            ColumnType::Decimal(width, scale) => write!(f, "decimal({},{})", width, scale),
+            ColumnType::Date => write!(f, "date"),
+            ColumnType::Time => write!(f, "time"),
+            ColumnType::Timestamp => write!(f, "timestamp"),
+            ColumnType::TimestampTz => write!(f, "timestamptz"),
+            ColumnType::Json => write!(f, "json"),
+            ColumnType::List(element) => write!(f, "{}[]", element),
+            ColumnType::Struct(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, t)| {
+                        if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                            format!("{} {}", name, t)
+                        } else {
+                            format!("\"{}\" {}", name, t)
+                        }
+                    })
+                    .collect();
+                write!(f, "struct({})", fields.join(", "))
+            }
+            ColumnType::Map(key, value) => write!(f, "map({}, {})", key, value),
+        }
+    }
+}
+
+/// DuckDB's JSON type is a VARCHAR carrying the "JSON" alias, which is what the json
+/// extension's `->>`, `json_extract` etc. look for.
+fn json_logical_type() -> LogicalTypeHandle {
+    unsafe {
+        let ptr = ffi::duckdb_create_logical_type(ffi::DUCKDB_TYPE_DUCKDB_TYPE_VARCHAR);
+        ffi::duckdb_logical_type_set_alias(ptr, c"JSON".as_ptr());
+        LogicalTypeHandle::new(ptr)
+    }
+}
+
+/// Whether `value` can be written to a column of `column_type` without being lost.
+/// Nulls fit every type.
+pub fn value_fits(value: &JsValue, column_type: &ColumnType) -> bool {
+    match (column_type, value) {
+        (_, JsValue::Null) => true,
+        (ColumnType::Varchar | ColumnType::Json, _) => true,
+        (ColumnType::Boolean, v) => to_bool(v).is_some(),
+        (ColumnType::Tinyint, v) => to_i64(v).is_some_and(|i| i8::try_from(i).is_ok()),
+        (ColumnType::Smallint, v) => to_i64(v).is_some_and(|i| i16::try_from(i).is_ok()),
+        (ColumnType::Integer, v) => to_i64(v).is_some_and(|i| i32::try_from(i).is_ok()),
+        (ColumnType::Bigint, v) => to_i64(v).is_some(),
+        (ColumnType::Float | ColumnType::Double, v) => to_f64(v).is_some(),
+        (ColumnType::Decimal(width, scale), v) => to_decimal(v, *width, *scale).is_some(),
+        (ColumnType::Date, v) => to_date_days(v).is_some(),
+        (ColumnType::Time, v) => to_time_micros(v).is_some(),
+        (ColumnType::Timestamp | ColumnType::TimestampTz, v) => to_timestamp_micros(v).is_some(),
+        (ColumnType::List(element), JsValue::List(items)) => items.iter().all(|item| value_fits(item, element)),
+        (ColumnType::Struct(fields), JsValue::Object(_)) => fields
+            .iter()
+            .all(|(name, t)| value.get(name).is_none_or(|field| value_fits(field, t))),
+        (ColumnType::Map(key_type, value_type), JsValue::Object(entries)) => entries.iter().all(|(key, field)| {
+            map_key_fits(key, key_type) && value_fits(field, value_type)
+        }),
+        _ => false,
+    }
+}
+
+/// Whether an object key converts to a non-NULL map key of `key_type`.
+fn map_key_fits(key: &str, key_type: &ColumnType) -> bool {
+    value_fits(&JsValue::String(key.to_string()), key_type)
+}
+
+/// Checks that every value in `rows` fits its column, describing the first one that doesn't.
+pub fn check_rows(rows: &[JsRow], columns: &[(String, ColumnType)]) -> Result<(), String> {
+    match columns.iter().find_map(|(name, column_type)| first_misfit(rows, name, column_type)) {
+        Some(problem) => Err(problem),
+        None => Ok(()),
+    }
+}
+
+/// Describes how `rows` disagree with `columns`: columns that no row has and the first value
+/// of each column that can't be converted. Empty if the rows conform (or there are none).
+pub fn conformance_problems(rows: &[JsRow], columns: &[(String, ColumnType)]) -> Vec<String> {
+    if rows.is_empty() {
+        return Vec::new();
+    }
+
+    let mut problems = Vec::new();
+    let missing: Vec<&str> = columns
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| rows.iter().all(|row| row.get(name).is_none()))
+        .collect();
+    if !missing.is_empty() {
+        // Unknown keys are usually the same columns under different names
+        let mut unknown: Vec<&str> = Vec::new();
+        for (key, _) in rows.iter().flat_map(JsRow::iter) {
+            if !columns.iter().any(|(name, _)| name == key) && !unknown.contains(&key.as_str()) {
+                unknown.push(key);
+            }
+        }
+        let mut problem = format!("Columns missing from every row: {}", missing.join(", "));
+        if !unknown.is_empty() {
+            problem.push_str(&format!(" (rows have keys not in the schema: {})", unknown.join(", ")));
+        }
+        problems.push(problem);
+    }
+
+    problems.extend(columns.iter().filter_map(|(name, column_type)| first_misfit(rows, name, column_type)));
+    problems
+}
+
+fn first_misfit(rows: &[JsRow], name: &str, column_type: &ColumnType) -> Option<String> {
+    rows.iter().enumerate().find_map(|(row_idx, row)| {
+        let value = row.get(name)?;
+        (!value_fits(value, column_type)).then(|| {
+            format!(
+                "Column '{}' expects {}, but row {} has {}",
+                name,
+                column_type,
+                row_idx + 1,
+                value.to_json()
+            )
+        })
+    })
+}
+
+/// A type inferred from sampled values, before nulls and empty objects are resolved.
+#[derive(Debug, Clone, PartialEq)]
+enum Inferred {
+    /// Only nulls seen so far
+    Unknown,
+    Scalar(ColumnType),
+    List(Box<Inferred>),
+    Struct(Vec<(String, Inferred)>),
+}
+
+/// DuckDB's ANY type, for named parameters such as `columns` that accept struct, map or
+/// varchar values.
+pub fn any_logical_type() -> LogicalTypeHandle {
+    unsafe { LogicalTypeHandle::new(ffi::duckdb_create_logical_type(ffi::DUCKDB_TYPE_DUCKDB_TYPE_ANY)) }
+}
+
+/// Infers columns from `rows`: columns appear in the order their keys are first seen, and each
+/// column's type is widened until every value fits (e.g. bigint and double become double;
+/// incompatible values fall back to varchar, or json when objects or arrays are involved).
+pub fn infer_columns(rows: &[JsRow]) -> Vec<(String, ColumnType)> {
+    let mut columns: Vec<(String, Inferred)> = Vec::new();
+    for row in rows {
+        merge_fields(&mut columns, row.iter());
+    }
+    columns.into_iter().map(|(name, t)| (name, t.resolve())).collect()
+}
+
+fn merge_fields<'a>(fields: &mut Vec<(String, Inferred)>, values: impl Iterator<Item = &'a (String, JsValue)>) {
+    for (name, value) in values {
+        let value_type = Inferred::of(value);
+        match fields.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing)) => *existing = Inferred::widen(std::mem::replace(existing, Inferred::Unknown), value_type),
+            None => fields.push((name.clone(), value_type)),
+        }
+    }
+}
+
+impl Inferred {
+    fn of(value: &JsValue) -> Self {
+        match value {
+            JsValue::Null => Inferred::Unknown,
+            JsValue::String(_) => Inferred::Scalar(ColumnType::Varchar),
+            JsValue::Float(_) => Inferred::Scalar(ColumnType::Double),
+            JsValue::Integer(_) => Inferred::Scalar(ColumnType::Bigint),
+            JsValue::Boolean(_) => Inferred::Scalar(ColumnType::Boolean),
+            JsValue::List(items) => Inferred::List(Box::new(
+                items.iter().map(Inferred::of).fold(Inferred::Unknown, Inferred::widen),
+            )),
+            JsValue::Object(fields) => {
+                let mut merged = Vec::new();
+                merge_fields(&mut merged, fields.iter());
+                Inferred::Struct(merged)
+            }
+        }
+    }
+
+    fn widen(a: Inferred, b: Inferred) -> Inferred {
+        match (a, b) {
+            (Inferred::Unknown, other) | (other, Inferred::Unknown) => other,
+            (Inferred::Scalar(a), Inferred::Scalar(b)) => Inferred::Scalar(match (a, b) {
+                (a, b) if a == b => a,
+                (ColumnType::Bigint, ColumnType::Double) | (ColumnType::Double, ColumnType::Bigint) => ColumnType::Double,
+                (ColumnType::Json, _) | (_, ColumnType::Json) => ColumnType::Json,
+                _ => ColumnType::Varchar,
+            }),
+            (Inferred::List(a), Inferred::List(b)) => Inferred::List(Box::new(Inferred::widen(*a, *b))),
+            (Inferred::Struct(mut a), Inferred::Struct(b)) => {
+                for (name, field_type) in b {
+                    match a.iter_mut().find(|(n, _)| *n == name) {
+                        Some((_, existing)) => {
+                            *existing = Inferred::widen(std::mem::replace(existing, Inferred::Unknown), field_type)
+                        }
+                        None => a.push((name, field_type)),
+                    }
+                }
+                Inferred::Struct(a)
+            }
+            // Objects or arrays mixed with other kinds of values
+            _ => Inferred::Scalar(ColumnType::Json),
+        }
+    }
+
+    fn resolve(self) -> ColumnType {
+        match self {
+            Inferred::Unknown => ColumnType::Varchar,
+            Inferred::Scalar(t) => t,
+            Inferred::List(element) => ColumnType::List(Box::new(element.resolve())),
+            // DuckDB has no empty structs
+            Inferred::Struct(fields) if fields.is_empty() => ColumnType::Json,
+            Inferred::Struct(fields) => {
+                ColumnType::Struct(fields.into_iter().map(|(name, t)| (name, t.resolve())).collect())
+            }
+        }
+    }
+}
+
+fn parse_type(data_type: &str, strict: bool) -> Option<ColumnType> {
+    let data_type = data_type.trim();
+    if let Some(element) = data_type.strip_suffix("[]") {
+        return Some(ColumnType::List(Box::new(parse_type(element, strict)?)));
+    }
+
+    if let Some((name, args)) = split_type_args(data_type) {
+        match name.to_lowercase().as_str() {
+            "decimal" | "numeric" => {
+                return parse_decimal_args(args).or((!strict).then_some(ColumnType::Decimal(18, 3)));
+            }
+            "list" | "array" => return Some(ColumnType::List(Box::new(parse_type(args, strict)?))),
+            "map" => {
+                if let [key, value] = split_top_level(args)[..] {
+                    return Some(ColumnType::Map(
+                        Box::new(parse_type(key, strict)?),
+                        Box::new(parse_type(value, strict)?),
+                    ));
+                }
+            }
+            "struct" | "row" => {
+                let fields = split_top_level(args)
+                    .into_iter()
+                    .map(|field| parse_struct_field(field, strict))
+                    .collect::<Option<Vec<_>>>()?;
+                if !fields.is_empty() {
+                    return Some(ColumnType::Struct(fields));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let column_type = match data_type.to_lowercase().as_str() {
+        "varchar" | "text" | "string" => ColumnType::Varchar,
+        "boolean" | "bool" => ColumnType::Boolean,
+        "tinyint" | "int1" => ColumnType::Tinyint,
+        "smallint" | "int2" | "short" => ColumnType::Smallint,
+        "integer" | "int" | "int4" => ColumnType::Integer,
+        "bigint" | "int8" | "long" => ColumnType::Bigint,
+        "float" | "float4" | "real" => ColumnType::Float,
+        "double" | "float8" | "number" => ColumnType::Double,
+        "decimal" | "numeric" => ColumnType::Decimal(18, 3),
+        "date" => ColumnType::Date,
+        "time" => ColumnType::Time,
+        "timestamp" | "datetime" => ColumnType::Timestamp,
+        "timestamptz" | "timestamp with time zone" => ColumnType::TimestampTz,
+        "json" => ColumnType::Json,
+        _ if strict => return None,
+        _ => ColumnType::Varchar,
+    };
+    Some(column_type)
+}
+
+/// Splits `name(args)` into its name and the text between the outer parentheses.
+fn split_type_args(data_type: &str) -> Option<(&str, &str)> {
+    let open = data_type.find('(')?;
+    let args = data_type[open + 1..].strip_suffix(')')?;
+    Some((data_type[..open].trim(), args))
+}
+
+/// Splits on commas that aren't nested inside brackets or quotes.
+fn split_top_level(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while let Some(idx) = find_top_level(&args[start..], &[',']) {
+        parts.push(args[start..start + idx].trim());
+        start += idx + 1;
+    }
+    parts.push(args[start..].trim());
+    parts.into_iter().filter(|p| !p.is_empty()).collect()
+}
+
+/// Finds the first of `targets` that isn't nested inside brackets or quotes.
+fn find_top_level(text: &str, targets: &[char]) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    for (idx, c) in text.char_indices() {
+        match c {
+            _ if quote == Some(c) => quote = None,
+            _ if quote.is_some() => {}
+            '"' | '\'' => quote = Some(c),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ if depth == 0 && targets.contains(&c) => return Some(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(text: &str) -> &str {
+    let text = text.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = text.strip_prefix(quote).and_then(|t| t.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    text
+}
+
+/// Parses an explicit column list as given to the `columns` named parameter: a struct or map
+/// literal as DuckDB renders it (`{'price': DOUBLE}` or `{price=DOUBLE}`), or a plain
+/// `price double, ts timestamp` list.
+pub fn parse_column_spec(spec: &str) -> Result<Vec<(String, ColumnType)>, String> {
+    let spec = spec.trim();
+    let inner = spec.strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap_or(spec);
+
+    let mut columns = Vec::new();
+    for entry in split_top_level(inner) {
+        let (name, data_type) = match find_top_level(entry, &[':', '=']) {
+            Some(idx) => (&entry[..idx], &entry[idx + 1..]),
+            None => entry
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("Invalid column definition '{}', expected a name and a type", entry))?,
+        };
+        let (name, data_type) = (unquote(name), unquote(data_type));
+        let column_type = ColumnType::try_parse(data_type)
+            .ok_or_else(|| format!("Unknown type '{}' for column '{}'", data_type, name))?;
+        columns.push((name.to_string(), column_type));
+    }
+
+    if columns.is_empty() {
+        return Err("columns must list at least one column".to_string());
+    }
+    Ok(columns)
+}
+
+/// Parses a struct field written as `name type`, `name: type` or `"quoted name" type`.
+fn parse_struct_field(field: &str, strict: bool) -> Option<(String, ColumnType)> {
+    let field = field.trim();
+    let (name, rest) = if let Some(quoted) = field.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        (&quoted[..end], &quoted[end + 1..])
+    } else {
+        let end = field.find(|c: char| c.is_whitespace() || c == ':')?;
+        (&field[..end], &field[end..])
+    };
+    let data_type = rest.trim_start().trim_start_matches(':').trim();
+    if name.is_empty() || data_type.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), parse_type(data_type, strict)?))
+}
+
+fn parse_decimal_args(args: &str) -> Option<ColumnType> {
+    let mut parts = args.split(',').map(|p| p.trim().parse::<u8>());
+    let width = parts.next()?.ok()?;
+    let scale = match parts.next() {
+        Some(scale) => scale.ok()?,
+        None => 0,
+    };
+    if width == 0 || width > 38 || scale > width {
+        return None;
+    }
+    Some(ColumnType::Decimal(width, scale))
+}
+
+/// Writes one output column, picking the kind of vector the column type needs.
+pub fn write_column(
+    output: &mut DataChunkHandle,
+    col_idx: usize,
+    column_type: &ColumnType,
+    values: &[Option<&JsValue>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match column_type {
+        ColumnType::List(_) | ColumnType::Map(..) => write_list_vector(&mut output.list_vector(col_idx), column_type, values),
+        ColumnType::Struct(_) => write_struct_vector(&mut output.struct_vector(col_idx), column_type, values),
+        _ => write_flat_vector(&mut output.flat_vector(col_idx), column_type, values),
+    }
+}
+
+/// Writes lists (JavaScript arrays) or maps (JavaScript objects). Anything else becomes NULL.
+fn write_list_vector(
+    vector: &mut ListVector,
+    column_type: &ColumnType,
+    values: &[Option<&JsValue>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match column_type {
+        ColumnType::List(element_type) => {
+            let mut elements: Vec<Option<&JsValue>> = Vec::new();
+            for (idx, value) in values.iter().enumerate() {
+                match value {
+                    Some(JsValue::List(items)) => {
+                        vector.set_entry(idx, elements.len(), items.len());
+                        elements.extend(items.iter().map(Some));
+                    }
+                    _ => vector.set_null(idx),
+                }
+            }
+            write_list_child(vector, element_type, &elements)?;
+            vector.set_len(elements.len());
+        }
+        ColumnType::Map(key_type, value_type) => {
+            // A map is a list of {key, value} structs; object keys are always strings in JavaScript.
+            // Map keys can't be NULL, so entries whose key doesn't convert are left out.
+            let mut keys: Vec<JsValue> = Vec::new();
+            let mut map_values: Vec<Option<&JsValue>> = Vec::new();
+            for (idx, value) in values.iter().enumerate() {
+                match value {
+                    Some(JsValue::Object(fields)) => {
+                        let offset = keys.len();
+                        for (key, value) in fields.iter().filter(|(key, _)| map_key_fits(key, key_type)) {
+                            keys.push(JsValue::String(key.clone()));
+                            map_values.push(Some(value));
+                        }
+                        vector.set_entry(idx, offset, keys.len() - offset);
+                    }
+                    _ => vector.set_null(idx),
+                }
+            }
+            let key_refs: Vec<Option<&JsValue>> = keys.iter().map(Some).collect();
+            let entries = vector.struct_child(keys.len());
+            write_struct_child(&entries, 0, key_type, &key_refs)?;
+            write_struct_child(&entries, 1, value_type, &map_values)?;
+            vector.set_len(keys.len());
+        }
+        _ => unreachable!("write_list_vector called for {:?}", column_type),
+    }
+    Ok(())
+}
+
+/// Writes structs (JavaScript objects). Anything else becomes NULL.
+fn write_struct_vector(
+    vector: &mut StructVector,
+    column_type: &ColumnType,
+    values: &[Option<&JsValue>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ColumnType::Struct(fields) = column_type else {
+        unreachable!("write_struct_vector called for {:?}", column_type);
+    };
+    for (field_idx, (name, field_type)) in fields.iter().enumerate() {
+        let field_values: Vec<Option<&JsValue>> = values.iter().map(|v| v.and_then(|v| v.get(name))).collect();
+        write_struct_child(vector, field_idx, field_type, &field_values)?;
+    }
+    for (idx, value) in values.iter().enumerate() {
+        if !matches!(value, Some(JsValue::Object(_))) {
+            vector.set_null(idx);
+        }
+    }
+    Ok(())
+}
+
+fn write_list_child(
+    list: &ListVector,
+    element_type: &ColumnType,
+    values: &[Option<&JsValue>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match element_type {
+        ColumnType::List(_) | ColumnType::Map(..) => {
+            // `child` reserves room for the elements; `list_child` alone doesn't
+            list.child(values.len());
+            write_list_vector(&mut list.list_child(), element_type, values)
+        }
+        ColumnType::Struct(_) => write_struct_vector(&mut list.struct_child(values.len()), element_type, values),
+        _ => write_flat_vector(&mut list.child(values.len()), element_type, values),
+    }
+}
+
+fn write_struct_child(
+    parent: &StructVector,
+    field_idx: usize,
+    field_type: &ColumnType,
+    values: &[Option<&JsValue>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match field_type {
+        ColumnType::List(_) | ColumnType::Map(..) => {
+            write_list_vector(&mut parent.list_vector_child(field_idx), field_type, values)
+        }
+        ColumnType::Struct(_) => write_struct_vector(&mut parent.struct_vector_child(field_idx), field_type, values),
+        _ => write_flat_vector(&mut parent.child(field_idx, values.len()), field_type, values),
+    }
+}
+
+/// Writes `values` into a flat vector of type `column_type`, converting each JavaScript value
+/// to the column's representation. Values that are missing, `null`, or can't be converted
+/// become SQL NULLs.
+pub fn write_flat_vector(
+    vector: &mut FlatVector,
+    column_type: &ColumnType,
+    values: &[Option<&JsValue>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match column_type {
+        ColumnType::Varchar => {
+            for (idx, value) in values.iter().enumerate() {
+                match value.and_then(to_varchar) {
+                    Some(value) => vector.insert(idx, value.as_str()),
+                    None => vector.set_null(idx),
+                }
+            }
+        }
+        ColumnType::Json => {
+            for (idx, value) in values.iter().enumerate() {
+                match value {
+                    Some(JsValue::Null) | None => vector.set_null(idx),
+                    Some(value) => vector.insert(idx, value.to_json().to_string().as_str()),
+                }
+            }
+        }
+        ColumnType::Boolean => write_slice(vector, values, to_bool),
+        ColumnType::Tinyint => write_slice(vector, values, |v| to_i64(v).and_then(|i| i8::try_from(i).ok())),
+        ColumnType::Smallint => write_slice(vector, values, |v| to_i64(v).and_then(|i| i16::try_from(i).ok())),
+        ColumnType::Integer => write_slice(vector, values, |v| to_i64(v).and_then(|i| i32::try_from(i).ok())),
+        ColumnType::Bigint => write_slice(vector, values, to_i64),
+        ColumnType::Float => write_slice(vector, values, |v| to_f64(v).map(|f| f as f32)),
+        ColumnType::Double => write_slice(vector, values, to_f64),
+        ColumnType::Decimal(width, scale) => {
+            let scaled = |v: &JsValue| to_decimal(v, *width, *scale);
+            // DuckDB stores decimals in the smallest integer that fits the width
+            match *width {
+                1..=4 => write_slice(vector, values, |v| scaled(v).and_then(|d| i16::try_from(d).ok())),
+                5..=9 => write_slice(vector, values, |v| scaled(v).and_then(|d| i32::try_from(d).ok())),
+                10..=18 => write_slice(vector, values, |v| scaled(v).and_then(|d| i64::try_from(d).ok())),
+                _ => write_slice(vector, values, scaled),
+            }
+        }
+        ColumnType::Date => write_slice(vector, values, to_date_days),
+        ColumnType::Time => write_slice(vector, values, to_time_micros),
+        ColumnType::Timestamp => write_slice(vector, values, to_timestamp_micros),
+        ColumnType::TimestampTz => write_slice(vector, values, to_timestamp_micros),
+    }
+    Ok(())
+}
+
+/// Writes converted values into the vector's data, marking rows that have no value as NULL.
+fn write_slice<T: Copy>(vector: &mut FlatVector, values: &[Option<&JsValue>], convert: impl Fn(&JsValue) -> Option<T>) {
+    let mut nulls = Vec::new();
+    let slice = vector.as_mut_slice::<T>();
+    for (idx, value) in values.iter().enumerate() {
+        match value.and_then(&convert) {
+            Some(value) => slice[idx] = value,
+            None => nulls.push(idx),
+        }
+    }
+    for idx in nulls {
+        vector.set_null(idx);
+    }
+}
+
+/// Text for a varchar column; `None` for JavaScript `null`.
+pub fn to_varchar(value: &JsValue) -> Option<String> {
+    match value {
+        JsValue::String(s) => Some(s.clone()),
+        JsValue::Float(f) => Some(f.to_string()),
+        JsValue::Integer(i) => Some(i.to_string()),
+        JsValue::Boolean(b) => Some(b.to_string()),
+        JsValue::List(_) | JsValue::Object(_) => Some(value.to_json().to_string()),
+        JsValue::Null => None,
+    }
+}
+
+fn to_bool(value: &JsValue) -> Option<bool> {
+    match value {
+        JsValue::Boolean(b) => Some(*b),
+        JsValue::Integer(i) => Some(*i != 0),
+        JsValue::Float(f) => Some(*f != 0.0),
+        JsValue::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "1" => Some(true),
+            "false" | "f" | "no" | "n" | "0" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Integers, and floats without a fractional part; `3.7` doesn't fit an integer column.
+fn to_i64(value: &JsValue) -> Option<i64> {
+    match value {
+        JsValue::Integer(i) => Some(*i),
+        JsValue::Float(f) => float_to_i64(*f),
+        JsValue::Boolean(b) => Some(*b as i64),
+        JsValue::String(s) => {
+            let s = s.trim();
+            s.parse::<i64>().ok().or_else(|| s.parse::<f64>().ok().and_then(float_to_i64))
+        }
+        _ => None,
+    }
+}
+
+fn float_to_i64(f: f64) -> Option<i64> {
+    // i64::MAX as f64 rounds up to 2^63, which is out of range
+    (f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64).then_some(f as i64)
+}
+
+fn to_f64(value: &JsValue) -> Option<f64> {
+    match value {
+        JsValue::Float(f) => Some(*f),
+        JsValue::Integer(i) => Some(*i as f64),
+        JsValue::Boolean(b) => Some(*b as i64 as f64),
+        JsValue::String(s) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+/// The value scaled by `10^scale`, if it has at most `width` digits.
+fn to_decimal(value: &JsValue, width: u8, scale: u8) -> Option<i128> {
+    let factor = 10f64.powi(scale as i32);
+    let scaled = match value {
+        JsValue::Integer(i) => (*i as i128).checked_mul(10i128.pow(scale as u32)),
+        other => to_f64(other)
+            .filter(|f| f.is_finite())
+            .map(|f| (f * factor).round() as i128),
+    }?;
+    (scaled.unsigned_abs() < 10u128.pow(width as u32)).then_some(scaled)
+}
+
+/// Interprets a number as seconds, milliseconds or microseconds since the Unix epoch,
+/// depending on its magnitude (JavaScript's `Date.now()` is in milliseconds).
+fn epoch_to_micros(n: f64) -> Option<i64> {
+    if !n.is_finite() {
+        return None;
+    }
+    let micros = if n.abs() >= 1e14 {
+        n
+    } else if n.abs() >= 1e11 {
+        n * 1_000.0
+    } else {
+        n * 1_000_000.0
+    };
+    Some(micros.round() as i64)
+}
+
+/// Parses ISO-8601 style timestamps. Values with an offset are converted to UTC; values
+/// without one are taken as they are.
+fn parse_timestamp(s: &str) -> Option<NaiveDateTime> {
+    let s = s.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(dt.naive_utc());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+            return Some(dt);
+        }
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f%#z", "%Y-%m-%dT%H:%M:%S%.f%#z"] {
+        if let Ok(dt) = DateTime::parse_from_str(s, format) {
+            return Some(dt.naive_utc());
+        }
+    }
+    parse_date(s).and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    let s = s.trim();
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y/%m/%d"))
+        .ok()
+}
+
+fn to_timestamp_micros(value: &JsValue) -> Option<i64> {
+    match value {
+        JsValue::String(s) => parse_timestamp(s).map(|dt| dt.and_utc().timestamp_micros()),
+        JsValue::Integer(i) => epoch_to_micros(*i as f64),
+        JsValue::Float(f) => epoch_to_micros(*f),
+        _ => None,
+    }
+}
+
+fn to_date_days(value: &JsValue) -> Option<i32> {
+    let date = match value {
+        JsValue::String(s) => parse_date(s).or_else(|| parse_timestamp(s).map(|dt| dt.date())),
+        other => to_timestamp_micros(other)
+            .and_then(DateTime::from_timestamp_micros)
+            .map(|dt| dt.date_naive()),
+    }?;
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1)?;
+    i32::try_from((date - epoch).num_days()).ok()
+}
+
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+fn to_time_micros(value: &JsValue) -> Option<i64> {
+    let time = match value {
+        JsValue::String(s) => {
+            let s = s.trim();
+            NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+                .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+                .ok()
+                .or_else(|| parse_timestamp(s).map(|dt| dt.time()))?
+        }
+        // Numbers are taken as seconds since midnight, up to 24:00:00
+        JsValue::Integer(i) => return i.checked_mul(1_000_000).filter(|micros| (0..=MICROS_PER_DAY).contains(micros)),
+        JsValue::Float(f) if (0.0..=86_400.0).contains(f) => return Some((f * 1_000_000.0).round() as i64),
+        _ => return None,
+    };
+    Some(time.num_seconds_from_midnight() as i64 * 1_000_000 + (time.nanosecond() as i64 % 1_000_000_000) / 1_000)
+}